@@ -0,0 +1,93 @@
+//! Write latency compared to the original write path.
+//!
+//! `write_chunks` used to probe system memory with `System::new_all` on
+//!
+//! every write and commit each chunk in its own transaction, without a
+//!
+//! manifest. `baseline::write_chunks` is a copy of that path.
+
+use criterion::{
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use is2fp::db;
+
+/// The write path of the first release, kept as it was
+mod baseline {
+
+    use kn0sys_lmdb_rs::{
+        Database,
+        DbHandle,
+        Environment,
+        MdbError,
+    };
+    use log::{
+        error,
+        info,
+    };
+    use sysinfo::System;
+
+    /// Ratio of chunk size to available memory is 0.2 percent
+    const CHUNK_SIZE_MEMORY_RATIO: f32 = 0.2 * 0.01;
+
+    /// Write a key/value pair in its own transaction
+    fn write(e: &Environment, h: &DbHandle, k: &Vec<u8>, v: &Vec<u8>) -> Result<(), MdbError> {
+        info!("excecuting lmdb write");
+        if k.is_empty() {
+            error!("can't write empty key");
+            return Err(MdbError::NotFound);
+        }
+        let txn = e.new_transaction()?;
+        {
+            let db: Database = txn.bind(h);
+            db.set(k, v).unwrap_or_else(|_| error!("failed to set key: {:?}", k));
+        }
+        txn.commit()
+    }
+
+    /// Probe memory for the chunk size, then write each chunk under
+    ///
+    /// `key ++ index` with a separate transaction
+    pub fn write_chunks(e: &Environment, h: &DbHandle, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+        let s = System::new_all();
+        let chunk_size = (s.available_memory() as f32 * CHUNK_SIZE_MEMORY_RATIO) as usize;
+        let mut writes: usize = 1;
+        let mut index: usize = 0;
+        let length = v.len();
+        loop {
+            let mut old_key: Vec<u8> = k.to_vec();
+            let mut append: Vec<u8> = (writes - 1).to_be_bytes().to_vec();
+            old_key.append(&mut append);
+            if length > chunk_size && (length - index > chunk_size) {
+                // write chunks until the last value which is smaller than chunk_size
+                let _ = write(e, h, &old_key, &v[index..(chunk_size * writes)].to_vec());
+                index += chunk_size;
+                writes += 1;
+            } else {
+                write(e, h, &old_key, &v[index..length].to_vec())?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn write_values(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("is2fp-bench-{}", rand::random::<u64>()));
+    let env = db::DatabaseEnvironment::open_at(&path).expect("failed to open bench environment");
+    for (name, size) in [("256B", 256), ("16MiB", 16 * 1024 * 1024)] {
+        let v = vec![0u8; size];
+        c.bench_function(&format!("write_chunks {}", name), |b| {
+            b.iter(|| db::write_chunks(&env, "bench-key".as_bytes(), &v))
+        });
+        // baseline keys have no manifest and stay apart from the current layout
+        c.bench_function(&format!("write_chunks {}, baseline", name), |b| {
+            b.iter(|| baseline::write_chunks(&env.env, &env.handle, "bench-baseline".as_bytes(), &v))
+        });
+    }
+    drop(env);
+    let _ = std::fs::remove_dir_all(&path);
+}
+
+criterion_group!(benches, write_values);
+criterion_main!(benches);
@@ -0,0 +1,186 @@
+#![deny(missing_docs)]
+
+//! Pluggable I2P backends.
+//!
+//! A backend creates relay destinations, publishes the relay server on
+//!
+//! its destination and sends requests to other relays. Select one at
+//!
+//! startup with `IS2FP_I2P_BACKEND`.
+
+use crate::{
+    error as is2fp_error,
+    i2p,
+    loopback,
+    sam,
+};
+use futures::future::BoxFuture;
+use log::{
+    error,
+    info,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::sync::Arc;
+
+/// Environment variable selecting the I2P backend
+pub const IS2FP_I2P_BACKEND: &str = "IS2FP_I2P_BACKEND";
+
+/// Backend shared between the router thread and the network loop
+pub type SharedBackend = Arc<dyn I2pBackend>;
+
+/// Keys of a relay destination
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Destination {
+    /// Base 32 address, e.g. `abc...xyz.b32.i2p`
+    pub b32: String,
+    /// Secret key in the backend's own encoding
+    pub sk: String,
+}
+
+/// Operations the relay needs from an I2P router
+pub trait I2pBackend: Send + Sync {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+    /// Start or connect to the router. Blocks until it can build tunnels.
+    fn start_router(&self) -> Result<(), is2fp_error::Ip2pError>;
+    /// Whether the router is still up. Polled once tunnels are ready.
+    fn is_running(&self) -> bool;
+    /// Generate a new destination
+    fn create_destination(&self) -> Result<Destination, is2fp_error::Ip2pError>;
+    /// Check that `dest.sk` is a secret key in this backend's encoding
+    ///
+    /// and belongs to `dest.b32`
+    fn check_destination(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError>;
+    /// Publish the local relay server `port` on `dest`
+    fn start_server_tunnel(&self, dest: &Destination, port: u16) -> Result<(), is2fp_error::Ip2pError>;
+    /// Stop publishing `dest`
+    fn stop_server_tunnel(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError>;
+    /// Whether the shared client proxy accepts connections
+    fn check_proxy(&self) -> bool;
+    /// Replace the shared client proxy with a new one
+    fn restart_proxy(&self) -> Result<(), is2fp_error::Ip2pError>;
+    /// Stop the tunnels, and the router if the backend started it
+    fn stop(&self) -> Result<(), is2fp_error::Ip2pError>;
+    /// Create an outbound client destination, returning its id
+    fn open_client(&self) -> Result<String, is2fp_error::Ip2pError>;
+    /// Tear down a client destination created by `open_client`
+    fn close_client(&self, client: &str) -> Result<(), is2fp_error::Ip2pError>;
+    /// POST a JSON `body` to `path` on the relay at `b32`, returning the
+    ///
+    /// response body. Sent from `client` if given, otherwise from the
+    ///
+    /// shared client destination.
+    fn post_json<'a>(
+        &'a self,
+        client: Option<&'a str>,
+        b32: &'a str,
+        path: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, is2fp_error::Ip2pError>>;
+}
+
+/// Backend selected by `IS2FP_I2P_BACKEND`, `j4i2prs` (default), `sam`
+///
+/// or the `loopback` emulator
+pub fn from_env() -> Result<SharedBackend, is2fp_error::Ip2pError> {
+    let name = std::env::var(IS2FP_I2P_BACKEND).unwrap_or_default();
+    let backend: SharedBackend = match name.as_str() {
+        "" | "j4i2prs" => Arc::new(i2p::J4I2prsBackend::from_env()?),
+        "sam" => Arc::new(sam::SamBackend::from_env()?),
+        "loopback" => Arc::new(loopback::LoopbackBackend::from_env()),
+        _ => {
+            error!("unknown i2p backend: {}", name);
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+    };
+    info!("using i2p backend: {}", backend.name());
+    Ok(backend)
+}
+
+/// Backend that only records what it was asked to do
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct TestBackend {
+    /// b32 addresses of published destinations
+    pub(crate) published: std::sync::Mutex<Vec<String>>,
+    /// Open client destinations
+    pub(crate) clients: std::sync::Mutex<Vec<String>>,
+    /// Proxy down and requests failing until the proxy is restarted
+    pub(crate) offline: std::sync::atomic::AtomicBool,
+    /// Set once `stop` was called
+    pub(crate) stopped: std::sync::atomic::AtomicBool,
+}
+
+#[cfg(test)]
+impl I2pBackend for TestBackend {
+    fn name(&self) -> &'static str {
+        "test"
+    }
+    fn start_router(&self) -> Result<(), is2fp_error::Ip2pError> {
+        Ok(())
+    }
+    fn is_running(&self) -> bool {
+        true
+    }
+    fn create_destination(&self) -> Result<Destination, is2fp_error::Ip2pError> {
+        let n: u32 = rand::random();
+        Ok(Destination { b32: format!("{}.b32.i2p", n), sk: n.to_string() })
+    }
+    fn check_destination(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        if dest.b32 != format!("{}.b32.i2p", dest.sk) {
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+        Ok(())
+    }
+    fn start_server_tunnel(&self, dest: &Destination, _: u16) -> Result<(), is2fp_error::Ip2pError> {
+        self.published.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?.push(dest.b32.clone());
+        Ok(())
+    }
+    fn stop_server_tunnel(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        self.published
+            .lock()
+            .map_err(|_| is2fp_error::Ip2pError::I2P)?
+            .retain(|b32| b32 != &dest.b32);
+        Ok(())
+    }
+    fn check_proxy(&self) -> bool {
+        !self.offline.load(std::sync::atomic::Ordering::SeqCst)
+    }
+    fn restart_proxy(&self) -> Result<(), is2fp_error::Ip2pError> {
+        self.offline.store(false, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+    fn stop(&self) -> Result<(), is2fp_error::Ip2pError> {
+        self.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+        Ok(())
+    }
+    fn open_client(&self) -> Result<String, is2fp_error::Ip2pError> {
+        let client = format!("client-{}", rand::random::<u32>());
+        self.clients.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?.push(client.clone());
+        Ok(client)
+    }
+    fn close_client(&self, client: &str) -> Result<(), is2fp_error::Ip2pError> {
+        self.clients
+            .lock()
+            .map_err(|_| is2fp_error::Ip2pError::I2P)?
+            .retain(|c| c != client);
+        Ok(())
+    }
+    fn post_json<'a>(
+        &'a self,
+        _: Option<&'a str>,
+        _: &'a str,
+        _: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, is2fp_error::Ip2pError>> {
+        Box::pin(async move {
+            if !self.check_proxy() {
+                return Err(is2fp_error::Ip2pError::Relay);
+            }
+            Ok(body)
+        })
+    }
+}
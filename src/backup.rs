@@ -0,0 +1,193 @@
+#![deny(missing_docs)]
+
+//! Database backup, export and import.
+//!
+//! A backup holds every logical key (identity key, relays, inbox and
+//!
+//! pending fluff), sealed with a passphrase that is independent of the
+//!
+//! database passphrase.
+
+use crate::{
+    crypto,
+    db,
+    error as is2fp_error,
+    i2p,
+    migration,
+};
+use log::{
+    error,
+    info,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    fs::OpenOptions,
+    io::{
+        Read,
+        Write,
+    },
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+/// Environment variable for the backup file passphrase
+pub const IS2FP_BACKUP_PASSPHRASE: &str = "IS2FP_BACKUP_PASSPHRASE";
+/// Identifies is2fp backup files, also bound to the sealed payload
+const BACKUP_MAGIC: &[u8] = b"IS2FPBAK";
+/// Version of the backup file layout
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+/// Portable contents of a backup file
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct Backup {
+    format_version: u32,
+    schema_version: u32,
+    entries: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+/// Passphrase from `IS2FP_BACKUP_PASSPHRASE` or the prompt
+pub fn get_passphrase() -> Result<String, is2fp_error::Ip2pError> {
+    crypto::read_passphrase(IS2FP_BACKUP_PASSPHRASE, "backup")
+}
+
+/// Keys that describe the local environment rather than node data
+fn is_excluded(k: &[u8]) -> bool {
+    crypto::is_metadata_key(k)
+        || k == migration::SCHEMA_VERSION_KEY.as_bytes()
+        || k == i2p::I2P_STATUS.as_bytes()
+}
+
+/// Write every logical key of an environment to `path`, sealed with
+///
+/// `passphrase`. Returns the number of keys exported.
+pub fn export(l: &db::DatabaseEnvironment, path: &Path, passphrase: &str) -> Result<usize, is2fp_error::Ip2pError> {
+    let schema_version = migration::get_version(l).map_err(is2fp_error::Ip2pError::Database)?;
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = l
+        .scan_prefix(&[])
+        .map_err(is2fp_error::Ip2pError::Database)?
+        .into_iter()
+        .filter(|(k, _)| !is_excluded(k))
+        .collect();
+    let count = entries.len();
+    let backup = Backup {
+        format_version: BACKUP_FORMAT_VERSION,
+        schema_version,
+        entries,
+    };
+    let payload = bincode::serialize(&backup).map_err(is2fp_error::Ip2pError::Encode)?;
+    let mut bytes: Vec<u8> = BACKUP_MAGIC.to_vec();
+    bytes.extend_from_slice(&crypto::seal_with_passphrase(passphrase, BACKUP_MAGIC, &payload)?);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(db::FILE_MODE)
+        .open(path)
+        .map_err(|e| {
+            error!("failed to create backup {}: {}", path.display(), e);
+            is2fp_error::Ip2pError::Backup
+        })?;
+    file.write_all(&bytes).map_err(|e| {
+        error!("failed to write backup {}: {}", path.display(), e);
+        is2fp_error::Ip2pError::Backup
+    })?;
+    info!("exported {} keys to {}", count, path.display());
+    Ok(count)
+}
+
+/// Restore a backup sealed with `passphrase` into a fresh environment
+///
+/// and upgrade it to the current schema. The keys and schema version are
+///
+/// written in one transaction. Returns the number of keys imported.
+pub fn import(l: &db::DatabaseEnvironment, path: &Path, passphrase: &str) -> Result<usize, is2fp_error::Ip2pError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    OpenOptions::new()
+        .read(true)
+        .open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|e| {
+            error!("failed to read backup {}: {}", path.display(), e);
+            is2fp_error::Ip2pError::Backup
+        })?;
+    let not_backup = || {
+        error!("{} is not an is2fp backup", path.display());
+        is2fp_error::Ip2pError::Backup
+    };
+    let sealed = bytes.strip_prefix(BACKUP_MAGIC).ok_or_else(not_backup)?;
+    let payload = match crypto::open_with_passphrase(passphrase, BACKUP_MAGIC, sealed) {
+        Ok(payload) => payload,
+        Err(is2fp_error::Ip2pError::Passphrase) => {
+            error!("wrong backup passphrase");
+            return Err(is2fp_error::Ip2pError::Passphrase);
+        }
+        Err(_) => return Err(not_backup()),
+    };
+    let backup: Backup = bincode::deserialize(&payload).map_err(is2fp_error::Ip2pError::Decode)?;
+    if backup.format_version != BACKUP_FORMAT_VERSION {
+        error!("unsupported backup format version {}", backup.format_version);
+        return Err(is2fp_error::Ip2pError::Backup);
+    }
+    if backup.schema_version > migration::CURRENT_SCHEMA_VERSION {
+        error!("backup schema version {} is newer than this build", backup.schema_version);
+        return Err(is2fp_error::Ip2pError::Backup);
+    }
+    let existing_sk = l.read(i2p::APP_I2P_SK.as_bytes()).map_err(is2fp_error::Ip2pError::Database)?;
+    if !existing_sk.is_empty() {
+        error!("refusing to import over an existing relay identity");
+        return Err(is2fp_error::Ip2pError::Backup);
+    }
+    let count = backup.entries.len();
+    let mut entries = backup.entries;
+    let version = bincode::serialize(&backup.schema_version).map_err(is2fp_error::Ip2pError::Encode)?;
+    entries.push((migration::SCHEMA_VERSION_KEY.as_bytes().to_vec(), version));
+    // a failed import leaves the environment as it was
+    db::write_batch(l, &entries).map_err(is2fp_error::Ip2pError::Database)?;
+    migration::migrate(l).map_err(is2fp_error::Ip2pError::Database)?;
+    info!("imported {} keys from {}", count, path.display());
+    Ok(count)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn export_import_test() -> Result<(), is2fp_error::Ip2pError> {
+        let src = db::TempEnvironment::open().map_err(is2fp_error::Ip2pError::Database)?;
+        let dst = db::TempEnvironment::open().map_err(is2fp_error::Ip2pError::Database)?;
+        let sk = i2p::APP_I2P_SK.as_bytes();
+        let relay = "b32-test-backup".as_bytes();
+        db::write_chunks(&src, sk, "secret".as_bytes())
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        db::write_chunks(&src, relay, "relay.b32.i2p".as_bytes())
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        let path = std::env::temp_dir().join(format!("is2fp-backup-{}", rand::random::<u64>()));
+        // only the identity and the relay, environment metadata stays local
+        assert_eq!(2, export(&src, &path, "backup")?);
+        // the secret key isn't readable without the passphrase
+        let bytes = std::fs::read(&path).map_err(|_| is2fp_error::Ip2pError::Backup)?;
+        assert!(!bytes.windows(6).any(|w| w == "secret".as_bytes()));
+        assert!(matches!(
+            import(&dst, &path, "wrong"),
+            Err(is2fp_error::Ip2pError::Passphrase)
+        ));
+        assert_eq!(2, import(&dst, &path, "backup")?);
+        let _ = std::fs::remove_file(&path);
+        let actual = dst.read(sk).map_err(is2fp_error::Ip2pError::Database)?;
+        assert_eq!("secret".as_bytes().to_vec(), actual);
+        let actual = dst.read(relay).map_err(is2fp_error::Ip2pError::Database)?;
+        assert_eq!("relay.b32.i2p".as_bytes().to_vec(), actual);
+        // the destination now has an identity
+        let path = std::env::temp_dir().join(format!("is2fp-backup-{}", rand::random::<u64>()));
+        export(&src, &path, "backup")?;
+        assert!(import(&dst, &path, "backup").is_err());
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+}
@@ -0,0 +1,374 @@
+#![deny(missing_docs)]
+
+//! Optional encryption at rest for values written through `db::write_chunks`.
+//!
+//! The sealing key is derived from a passphrase with Argon2id and values
+//!
+//! are sealed with XChaCha20-Poly1305 using the logical key as associated data.
+
+use crate::{
+    db,
+    error as is2fp_error,
+};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{
+        Aead,
+        KeyInit,
+        Payload,
+    },
+    Key,
+    XChaCha20Poly1305,
+    XNonce,
+};
+use kn0sys_lmdb_rs::{
+    DbHandle,
+    Environment,
+    MdbError,
+};
+use log::{
+    error,
+    info,
+};
+use std::io::{
+    IsTerminal,
+    Write,
+};
+
+/// Environment variable to enable encryption when creating a database
+pub const IS2FP_ENCRYPT: &str = "IS2FP_ENCRYPT";
+/// Environment variable for the database passphrase
+pub const IS2FP_PASSPHRASE: &str = "IS2FP_PASSPHRASE";
+/// LMDB key for the key derivation salt (stored unsealed)
+const SALT_KEY: &str = "crypto-salt";
+/// LMDB key for the sealed passphrase check value (stored unsealed)
+const CHECK_KEY: &str = "crypto-check";
+/// LMDB key present while existing values are being sealed (stored unsealed)
+const SEALING_KEY: &str = "crypto-sealing";
+/// Plaintext sealed under `CHECK_KEY` to verify the passphrase
+const CHECK_VALUE: &[u8] = b"is2fp";
+/// Prefix identifying sealed values
+const SEALED_MAGIC: &[u8] = b"IS2FPSEAL1";
+const SALT_LENGTH: usize = 16;
+const NONCE_LENGTH: usize = 24;
+
+/// Seals and opens values with a passphrase-derived key
+pub struct Sealer {
+    cipher: XChaCha20Poly1305,
+}
+
+impl Sealer {
+    /// Derive the sealing key from a passphrase and salt
+    pub fn derive(passphrase: &str, salt: &[u8]) -> Result<Self, is2fp_error::Ip2pError> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| {
+                error!("key derivation failed: {:?}", e);
+                is2fp_error::Ip2pError::Crypto
+            })?;
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key));
+        key.fill(0);
+        Ok(Sealer { cipher })
+    }
+    /// Seal `v`, binding it to the logical key `k`
+    pub fn seal(&self, k: &[u8], v: &[u8]) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), Payload { msg: v, aad: k })
+            .map_err(|_| is2fp_error::Ip2pError::Crypto)?;
+        let mut sealed: Vec<u8> = SEALED_MAGIC.to_vec();
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+    /// Open a value sealed for the logical key `k`. Fails if the value
+    ///
+    /// was tampered with, moved to another key or sealed with another key.
+    pub fn open(&self, k: &[u8], v: &[u8]) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+        if !is_sealed(v) || v.len() < SEALED_MAGIC.len() + NONCE_LENGTH {
+            return Err(is2fp_error::Ip2pError::Crypto);
+        }
+        let (nonce, ciphertext) = v[SEALED_MAGIC.len()..].split_at(NONCE_LENGTH);
+        self.cipher
+            .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad: k })
+            .map_err(|_| is2fp_error::Ip2pError::Crypto)
+    }
+}
+
+/// Seal `v` for the logical key `k` with a key derived from `passphrase`
+///
+/// and a new salt. The salt is prepended, e.g. for files kept outside
+///
+/// the database.
+pub fn seal_with_passphrase(passphrase: &str, k: &[u8], v: &[u8]) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+    let salt: [u8; SALT_LENGTH] = rand::random();
+    let mut sealed: Vec<u8> = salt.to_vec();
+    sealed.extend_from_slice(&Sealer::derive(passphrase, &salt)?.seal(k, v)?);
+    Ok(sealed)
+}
+
+/// Open a value from `seal_with_passphrase`. Fails with `Passphrase` if
+///
+/// it was sealed with another passphrase or tampered with.
+pub fn open_with_passphrase(passphrase: &str, k: &[u8], v: &[u8]) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+    if v.len() < SALT_LENGTH {
+        return Err(is2fp_error::Ip2pError::Crypto);
+    }
+    let (salt, sealed) = v.split_at(SALT_LENGTH);
+    Sealer::derive(passphrase, salt)?
+        .open(k, sealed)
+        .map_err(|_| is2fp_error::Ip2pError::Passphrase)
+}
+
+/// Salt, passphrase check and sealing progress belong to one environment
+///
+/// and are never exported
+pub fn is_metadata_key(k: &[u8]) -> bool {
+    k == SALT_KEY.as_bytes() || k == CHECK_KEY.as_bytes() || k == SEALING_KEY.as_bytes()
+}
+
+/// Sealed values start with `SEALED_MAGIC`
+fn is_sealed(v: &[u8]) -> bool {
+    v.starts_with(SEALED_MAGIC)
+}
+
+/// Derive the sealing key for an environment. A new salt and passphrase
+///
+/// check are created if the environment is not encrypted yet.
+pub fn setup(
+    e: &Environment,
+    h: &DbHandle,
+    passphrase: &str,
+) -> Result<Sealer, is2fp_error::Ip2pError> {
+    let salt_key = SALT_KEY.as_bytes();
+    let check_key = CHECK_KEY.as_bytes();
+    let salt = db::DatabaseEnvironment::read_raw(e, h, salt_key)
+        .map_err(is2fp_error::Ip2pError::Database)?;
+    if salt.is_empty() {
+        info!("encrypting database");
+        let salt: [u8; SALT_LENGTH] = rand::random();
+        let sealer = Sealer::derive(passphrase, &salt)?;
+        let check = sealer.seal(check_key, CHECK_VALUE)?;
+        db::write_raw_chunks(e, h, salt_key, &salt).map_err(is2fp_error::Ip2pError::Database)?;
+        db::write_raw_chunks(e, h, check_key, &check)
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        return Ok(sealer);
+    }
+    let sealer = Sealer::derive(passphrase, &salt)?;
+    let check = db::DatabaseEnvironment::read_raw(e, h, check_key)
+        .map_err(is2fp_error::Ip2pError::Database)?;
+    match sealer.open(check_key, &check) {
+        Ok(v) if v == CHECK_VALUE => Ok(sealer),
+        _ => {
+            error!("wrong database passphrase");
+            Err(is2fp_error::Ip2pError::Passphrase)
+        }
+    }
+}
+
+/// Seal every existing plaintext value. `SEALING_KEY` is removed once
+///
+/// all values are sealed, an interrupted run resumes on the next start.
+fn seal_existing(e: &Environment, h: &DbHandle, sealer: &Sealer) -> Result<(), MdbError> {
+    for (k, v) in db::DatabaseEnvironment::scan_prefix_raw(e, h, &[])? {
+        if is_metadata_key(&k) || is_sealed(&v) {
+            continue;
+        }
+        let sealed = sealer.seal(&k, &v).map_err(|_| MdbError::Corrupted)?;
+        db::write_raw_chunks(e, h, &k, &sealed)?;
+    }
+    db::DatabaseEnvironment::delete(e, h, SEALING_KEY.as_bytes())
+}
+
+/// Whether sealing existing values was started and hasn't finished
+fn is_sealing(e: &Environment, h: &DbHandle) -> Result<bool, is2fp_error::Ip2pError> {
+    let v = db::DatabaseEnvironment::read_raw(e, h, SEALING_KEY.as_bytes())
+        .map_err(is2fp_error::Ip2pError::Database)?;
+    Ok(!v.is_empty())
+}
+
+/// Read the database passphrase from `IS2FP_PASSPHRASE` or prompt for it
+fn get_passphrase() -> Result<String, is2fp_error::Ip2pError> {
+    read_passphrase(IS2FP_PASSPHRASE, "database")
+}
+
+/// Read a passphrase from the environment variable `name`, otherwise
+///
+/// prompt for the `what` passphrase on stdin. Typing isn't echoed when
+///
+/// stdin is a terminal.
+pub fn read_passphrase(name: &str, what: &str) -> Result<String, is2fp_error::Ip2pError> {
+    if let Ok(passphrase) = std::env::var(name) {
+        return Ok(passphrase);
+    }
+    let prompt = format!("is2fp {} passphrase: ", what);
+    if std::io::stdin().is_terminal() {
+        return rpassword::prompt_password(prompt).map_err(|_| is2fp_error::Ip2pError::Passphrase);
+    }
+    // piped input, e.g. from a secrets manager
+    print!("{}", prompt);
+    std::io::stdout().flush().map_err(|_| is2fp_error::Ip2pError::Passphrase)?;
+    let mut passphrase = String::new();
+    std::io::stdin()
+        .read_line(&mut passphrase)
+        .map_err(|_| is2fp_error::Ip2pError::Passphrase)?;
+    Ok(passphrase.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Unlock a database if it is encrypted or `IS2FP_ENCRYPT` is set,
+///
+/// returning its sealer. Called by `DatabaseEnvironment::open` before
+///
+/// migrations run.
+pub fn init(e: &Environment, h: &DbHandle) -> Result<Option<Sealer>, is2fp_error::Ip2pError> {
+    let salt = db::DatabaseEnvironment::read_raw(e, h, SALT_KEY.as_bytes())
+        .map_err(is2fp_error::Ip2pError::Database)?;
+    let enable = !std::env::var(IS2FP_ENCRYPT).unwrap_or_default().is_empty();
+    let sealing = is_sealing(e, h)?;
+    if salt.is_empty() && !enable && !sealing {
+        return Ok(None);
+    }
+    let passphrase = get_passphrase()?;
+    if salt.is_empty() {
+        // recorded before the salt so an interrupted run always resumes
+        db::write_raw_chunks(e, h, SEALING_KEY.as_bytes(), &[1u8])
+            .map_err(is2fp_error::Ip2pError::Database)?;
+    }
+    let sealer = setup(e, h, &passphrase)?;
+    if salt.is_empty() || sealing {
+        seal_existing(e, h, &sealer).map_err(is2fp_error::Ip2pError::Database)?;
+    }
+    info!("database unlocked");
+    Ok(Some(sealer))
+}
+
+/// Unlock an encrypted database without changing it. Used for read-only
+///
+/// opens, databases that aren't encrypted are left as-is.
+pub fn unlock(e: &Environment, h: &DbHandle) -> Result<Option<Sealer>, is2fp_error::Ip2pError> {
+    let salt = db::DatabaseEnvironment::read_raw(e, h, SALT_KEY.as_bytes())
+        .map_err(is2fp_error::Ip2pError::Database)?;
+    if salt.is_empty() {
+        return Ok(None);
+    }
+    if is_sealing(e, h)? {
+        error!("database encryption didn't finish, open it read-write first");
+        return Err(is2fp_error::Ip2pError::Crypto);
+    }
+    // the salt exists so setup only verifies the passphrase
+    let sealer = setup(e, h, &get_passphrase()?)?;
+    info!("database unlocked");
+    Ok(Some(sealer))
+}
+
+/// Seal a value with `sealer`, the key of an encrypted database
+pub fn seal(sealer: Option<&Sealer>, k: &[u8], v: &[u8]) -> Result<Vec<u8>, MdbError> {
+    match sealer {
+        Some(sealer) => sealer.seal(k, v).map_err(|_| {
+            error!("failed to seal key {:?}", k);
+            MdbError::Corrupted
+        }),
+        None => Ok(v.to_vec()),
+    }
+}
+
+/// Open a value with `sealer`, the key of an encrypted database.
+///
+/// Plaintext values are only returned from databases that aren't
+///
+/// encrypted. Every value is sealed before the database is unlocked, so
+///
+/// plaintext in an encrypted database was written around the sealer and
+///
+/// is rejected.
+pub fn open(sealer: Option<&Sealer>, k: &[u8], v: Vec<u8>) -> Result<Vec<u8>, MdbError> {
+    if is_metadata_key(k) {
+        return Ok(v);
+    }
+    match (sealer, is_sealed(&v)) {
+        (None, false) => Ok(v),
+        (None, true) => {
+            error!("database is locked, can't open key {:?}", k);
+            Err(MdbError::StateError(String::from("database is locked")))
+        }
+        (Some(_), false) if v.is_empty() => Ok(v),
+        (Some(_), false) => {
+            error!("unsealed value for key {:?} in an encrypted database", k);
+            Err(MdbError::Corrupted)
+        }
+        (Some(sealer), true) => sealer.open(k, &v).map_err(|_| {
+            error!("failed to open key {:?}, value was tampered with", k);
+            MdbError::Corrupted
+        }),
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn seal_test() -> Result<(), is2fp_error::Ip2pError> {
+        let sealer = Sealer::derive("correct horse", &[7u8; SALT_LENGTH])?;
+        let k = "test-seal-key".as_bytes();
+        let v = "secret".as_bytes();
+        let sealed = sealer.seal(k, v)?;
+        assert!(is_sealed(&sealed));
+        assert_eq!(v.to_vec(), sealer.open(k, &sealed)?);
+        // tampered ciphertext
+        let mut tampered = sealed.clone();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(sealer.open(k, &tampered).is_err());
+        // value moved to another key
+        assert!(sealer.open("test-other-key".as_bytes(), &sealed).is_err());
+        // wrong key
+        let other = Sealer::derive("battery staple", &[7u8; SALT_LENGTH])?;
+        assert!(other.open(k, &sealed).is_err());
+        // salted with the passphrase
+        let sealed = seal_with_passphrase("correct horse", k, v)?;
+        assert_eq!(v.to_vec(), open_with_passphrase("correct horse", k, &sealed)?);
+        assert!(matches!(
+            open_with_passphrase("battery staple", k, &sealed),
+            Err(is2fp_error::Ip2pError::Passphrase)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn passphrase_test() -> Result<(), is2fp_error::Ip2pError> {
+        let db = db::TempEnvironment::open().map_err(is2fp_error::Ip2pError::Database)?;
+        setup(&db.env, &db.handle, "correct horse")?;
+        assert!(setup(&db.env, &db.handle, "correct horse").is_ok());
+        assert!(matches!(
+            setup(&db.env, &db.handle, "battery staple"),
+            Err(is2fp_error::Ip2pError::Passphrase)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn plaintext_test() -> Result<(), is2fp_error::Ip2pError> {
+        let db = db::TempEnvironment::open().map_err(is2fp_error::Ip2pError::Database)?;
+        let sealer = setup(&db.env, &db.handle, "correct horse")?;
+        let k = "test-plaintext-key".as_bytes();
+        // written around the sealer, e.g. by another tool
+        db::write_raw_chunks(&db.env, &db.handle, k, "secret".as_bytes())
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        let v = db::DatabaseEnvironment::read_raw(&db.env, &db.handle, k)
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        assert!(matches!(open(Some(&sealer), k, v.clone()), Err(MdbError::Corrupted)));
+        let sealed = sealer.seal(k, &v)?;
+        assert_eq!(v, open(Some(&sealer), k, sealed).map_err(is2fp_error::Ip2pError::Database)?);
+        // the salt is metadata and stays readable
+        let salt = db::DatabaseEnvironment::read_raw(&db.env, &db.handle, SALT_KEY.as_bytes())
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        assert!(open(Some(&sealer), SALT_KEY.as_bytes(), salt).is_ok());
+        Ok(())
+    }
+}
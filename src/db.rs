@@ -4,35 +4,117 @@
 
 extern crate kn0sys_lmdb_rs as lmdb;
 
+use crate::{
+    crypto,
+    migration,
+    utils,
+};
 use lmdb::*;
 use log::{
     error,
     info,
+    warn,
+};
+use std::{
+    fs::DirBuilder,
+    os::unix::fs::DirBuilderExt,
+    path::{
+        Path,
+        PathBuf,
+    },
 };
-use std::sync::LazyLock;
 use sysinfo::System;
 
 /// Ratio of map size to available memory is 20 percent
 const MAP_SIZE_MEMORY_RATIO: f32 = 0.2;
 /// Ratio of chunk size to available memory is 0.2 percent
 const CHUNK_SIZE_MEMORY_RATIO: f32 = MAP_SIZE_MEMORY_RATIO * 0.01;
+/// Chunk size used when an environment has no recorded chunk size
+const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Environment variable for the chunk size of new writes
+pub const IS2FP_CHUNK_SIZE: &str = "IS2FP_CHUNK_SIZE";
+/// LMDB key recording the chunk size of the environment
+const CHUNK_SIZE_KEY: &str = "meta-chunk-size";
+/// LMDB key set once chunk manifests live under `MANIFEST_PREFIX`
+pub(crate) const MANIFEST_LAYOUT_KEY: &str = "meta-manifest-layout";
+/// Reserved prefix of chunk manifests. Logical keys can't start with
+///
+/// a null byte, so manifests never collide with chunks.
+const MANIFEST_PREFIX: &[u8] = b"\0manifest/";
 /// LMDB Environment Variable
 const IS2FP_LMDB_ENV: &str = "IS2FP_LMDB_ENV";
+/// Environment variable for the data directory
+pub const IS2FP_DATA_DIR: &str = "IS2FP_DATA_DIR";
+/// Name of the application directory under the data home
+const APP_DIR: &str = "is2fp";
+/// Directories are only accessible by the owner
+const DIR_MODE: u32 = 0o700;
+/// Database, backup and identity files are only accessible by the owner
+pub(crate) const FILE_MODE: u32 = 0o600;
+
+/// Open the primary database selected by `IS2FP_LMDB_ENV`.
+///
+/// Call once at startup and share the environment, LMDB does not allow
+///
+/// opening the same environment twice in one process.
+pub fn open_default() -> Result<DatabaseEnvironment, MdbError> {
+    DatabaseEnvironment::open(&get_env_str())
+}
+
+/// Open the primary database read-only, see `DatabaseEnvironment::open_readonly`
+pub fn open_default_readonly() -> Result<DatabaseEnvironment, MdbError> {
+    DatabaseEnvironment::open_readonly(&get_env_str())
+}
+
+/// Name of the primary database from `IS2FP_LMDB_ENV`
+fn get_env_str() -> String {
+    std::env::var(IS2FP_LMDB_ENV).unwrap_or(String::from("test"))
+}
 
-/// Database lock is initialized on startup in order to cache the db handle
-pub static DATABASE_LOCK: LazyLock<DatabaseEnvironment> = LazyLock::new(|| {
-    let env_str = std::env::var(IS2FP_LMDB_ENV).unwrap_or(String::from("test"));
-    DatabaseEnvironment::open(&env_str).unwrap_or_else(|_| panic!("failed to initialize lmdb!"))
-});
+/// Resolve the data directory. In order of precedence:
+///
+/// `IS2FP_DATA_DIR`, the legacy `/home/{LMDB_USER}/.is2fp` if it already
+///
+/// exists, `$XDG_DATA_HOME/is2fp` and finally `$HOME/.local/share/is2fp`.
+pub fn get_data_dir() -> Result<PathBuf, MdbError> {
+    if let Ok(dir) = std::env::var(IS2FP_DATA_DIR) {
+        if !dir.is_empty() {
+            return Ok(PathBuf::from(dir));
+        }
+    }
+    let user: String = match std::env::var("LMDB_USER") {
+        Err(_) => std::env::var("USER").unwrap_or(String::from("user")),
+        Ok(user) => user,
+    };
+    info!("$LMDB_USER={}", user);
+    let legacy = PathBuf::from(format!("/home/{}/.{}", user, APP_DIR));
+    if legacy.is_dir() {
+        return Ok(legacy);
+    }
+    if let Ok(xdg) = std::env::var("XDG_DATA_HOME") {
+        if !xdg.is_empty() {
+            return Ok(PathBuf::from(xdg).join(APP_DIR));
+        }
+    }
+    match std::env::var("HOME") {
+        Ok(home) if !home.is_empty() => Ok(PathBuf::from(home).join(".local/share").join(APP_DIR)),
+        _ => {
+            error!("no data directory available, set {}", IS2FP_DATA_DIR);
+            Err(MdbError::StateError(String::from("no data directory available")))
+        }
+    }
+}
 
 /// The database environment for handling primary database operations.
 ///
-/// By default the database will be written to /home/user/.neveko/{ENV}/lmdb
+/// By default the database will be written to `get_data_dir()/{ENV}`
 pub struct DatabaseEnvironment {
     /// Represents LMDB Environment.
     pub env: Environment,
     /// DB handle.
     pub handle: DbHandle,
+    /// Seals and opens values when the environment is encrypted
+    sealer: Option<crypto::Sealer>,
 }
 
 impl DatabaseEnvironment {
@@ -41,61 +123,106 @@ impl DatabaseEnvironment {
     /// of available memory and can be set via the `LMDB_MAP_SIZE` environment
     /// variable.
     ///
-    /// The path of the user can be set with `LMDB_USER`.
+    /// The directory is resolved with `get_data_dir` and created if missing.
+    ///
+    /// Schema migrations run before the environment is returned.
     pub fn open(env_str: &str) -> Result<Self, MdbError> {
-        let s = System::new_all();
+        let file_path: PathBuf = get_data_dir()?.join(env_str);
+        DatabaseEnvironment::open_at(&file_path)
+    }
+    /// Opens environment in the directory `file_path`, see `open`
+    pub fn open_at(file_path: &Path) -> Result<Self, MdbError> {
+        DatabaseEnvironment::open_with(file_path, false)
+    }
+    /// Opens an existing environment read-only, see `open`.
+    ///
+    /// Safe to use while a node has the environment open, e.g. for exports.
+    ///
+    /// The environment is not created, migrated or encrypted. Writes fail.
+    pub fn open_readonly(env_str: &str) -> Result<Self, MdbError> {
+        let file_path: PathBuf = get_data_dir()?.join(env_str);
+        DatabaseEnvironment::open_at_readonly(&file_path)
+    }
+    /// Opens an existing environment in the directory `file_path` read-only
+    pub fn open_at_readonly(file_path: &Path) -> Result<Self, MdbError> {
+        DatabaseEnvironment::open_with(file_path, true)
+    }
+    fn open_with(file_path: &Path, read_only: bool) -> Result<Self, MdbError> {
+        // probe memory once, writes use the recorded chunk size
+        let mut s = System::new();
+        s.refresh_memory();
         let default_map_size: u64 =
             (s.available_memory() as f32 * MAP_SIZE_MEMORY_RATIO).floor() as u64;
-        let env_map_size: u64 = match std::env::var("LMDB_MAP_SIZE") {
-            Err(_) => default_map_size,
-            Ok(size) => size.parse::<u64>().unwrap_or(default_map_size),
-        };
+        let env_map_size: u64 = utils::get_env_parse("LMDB_MAP_SIZE").unwrap_or(default_map_size);
         info!("setting lmdb map size to: {}", env_map_size);
-        let user: String = match std::env::var("LMDB_USER") {
-            Err(_) => std::env::var("USER").unwrap_or(String::from("user")),
-            Ok(user) => user,
-        };
-        info!("$LMDB_USER={}", user);
         info!("excecuting lmdb open");
-        let file_path: String = format!("/home/{}/.{}/", user, "is2fp");
-        let env: Environment = EnvBuilder::new()
-            .map_size(env_map_size)
-            .open(format!("{}/{}", file_path, env_str), 0o777)
-            .unwrap_or_else(|_| panic!("could not open LMDB at {}", file_path));
-        let default: Result<DbHandle, MdbError> = env.get_default_db(DbFlags::empty());
-        if default.is_err() {
-            panic!("could not set db handle")
-        }
-        let handle: DbHandle = default?;
-        Ok(DatabaseEnvironment { env, handle })
-    }
-    /// Write a key/value pair to the database. It is not possible to
-    ///
-    /// write with empty keys.
-    fn write(e: &Environment, h: &DbHandle, k: &Vec<u8>, v: &Vec<u8>) -> Result<(), MdbError> {
-        info!("excecuting lmdb write");
-        if k.is_empty() {
-            error!("can't write empty key");
-            return Err(MdbError::NotFound);
+        let mut builder = EnvBuilder::new().map_size(env_map_size);
+        if read_only {
+            if !file_path.is_dir() {
+                error!("no database at {}", file_path.display());
+                return Err(MdbError::StateError(format!("no database at {}", file_path.display())));
+            }
+            builder = builder.flags(EnvCreateFlags::EnvCreateReadOnly);
+        } else {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(DIR_MODE)
+                .create(file_path)
+                .map_err(|e| {
+                    error!("could not create data directory {}: {}", file_path.display(), e);
+                    MdbError::StateError(format!("could not create {}", file_path.display()))
+                })?;
         }
-        let new_txn = e.new_transaction()?;
-        let txn = new_txn;
-        {
-            let db: Database = txn.bind(h);
-            let pair: Vec<(&Vec<u8>, &Vec<u8>)> = vec![(k, v)];
-            for &(key, value) in pair.iter() {
-                db.set(key, value)
-                    .unwrap_or_else(|_| error!("failed to set key: {:?}", k));
+        let env: Environment = builder.open(file_path, FILE_MODE).map_err(|e| {
+            error!("could not open LMDB at {}: {:?}", file_path.display(), e);
+            e
+        })?;
+        let handle: DbHandle = env.get_default_db(DbFlags::empty()).map_err(|e| {
+            error!("could not set db handle for {}: {:?}", file_path.display(), e);
+            e
+        })?;
+        if read_only {
+            let sealer = crypto::unlock(&env, &handle).map_err(|e| {
+                error!("could not unlock database: {:?}", e);
+                MdbError::StateError(String::from("could not unlock database"))
+            })?;
+            let db = DatabaseEnvironment { env, handle, sealer };
+            let version = migration::get_version(&db)?;
+            if !has_manifest_layout(&db.env, &db.handle)? || version != migration::CURRENT_SCHEMA_VERSION {
+                error!(
+                    "schema version {} needs migration to {}, open read-write first",
+                    version,
+                    migration::CURRENT_SCHEMA_VERSION
+                );
+                return Err(MdbError::StateError(format!("unsupported schema version {}", version)));
             }
+            return Ok(db);
         }
-        txn.commit()
+        init_chunk_size(&env, &handle, &s)?;
+        upgrade_manifests(&env, &handle)?;
+        let sealer = crypto::init(&env, &handle).map_err(|e| {
+            error!("could not unlock database: {:?}", e);
+            MdbError::StateError(String::from("could not unlock database"))
+        })?;
+        let db = DatabaseEnvironment { env, handle, sealer };
+        migration::migrate(&db)?;
+        Ok(db)
     }
     /// Read key from the database. If it doesn't exist then
     ///
     /// an empty vector will be returned. Treat all empty vectors
     ///
     /// from database operations as failures.
-    pub fn read(e: &Environment, h: &DbHandle, k: &Vec<u8>) -> Result<Vec<u8>, MdbError> {
+    ///
+    /// Sealed values are opened with the key unlocked when the
+    ///
+    /// environment was opened.
+    pub fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+        let result = DatabaseEnvironment::read_raw(&self.env, &self.handle, k)?;
+        crypto::open(self.sealer.as_ref(), k, result)
+    }
+    /// Read key from the database without opening sealed values
+    pub(crate) fn read_raw(e: &Environment, h: &DbHandle, k: &[u8]) -> Result<Vec<u8>, MdbError> {
         log::trace!("excecuting lmdb read");
         // don't try and read empty keys
         if k.is_empty() {
@@ -105,17 +232,7 @@ impl DatabaseEnvironment {
         let get_reader = e.get_reader();
         let reader: ReadonlyTransaction = get_reader?;
         let db: Database = reader.bind(h);
-        let mut result: Vec<u8> = Vec::new();
-        for num_writes in 0..usize::MAX {
-            let mut new_key: Vec<u8> = k.to_vec();
-            let mut key_count: Vec<u8> = (num_writes).to_be_bytes().to_vec();
-            new_key.append(&mut key_count);
-            let mut r = db.get::<Vec<u8>>(&new_key).unwrap_or_default();
-            if r.is_empty() {
-                break;
-            }
-            result.append(&mut r);
-        }
+        let result: Vec<u8> = read_chunks(&db, k)?;
         {
             if result.is_empty() {
                 log::trace!("failed to read key {:?} from db", k);
@@ -123,6 +240,41 @@ impl DatabaseEnvironment {
         }
         Ok(result)
     }
+    /// Read every logical key starting with `prefix` along with its
+    ///
+    /// reassembled value. Keys are returned in ascending order.
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, MdbError> {
+        DatabaseEnvironment::scan_prefix_raw(&self.env, &self.handle, prefix)?
+            .into_iter()
+            .map(|(k, v)| crypto::open(self.sealer.as_ref(), &k, v).map(|v| (k, v)))
+            .collect()
+    }
+    /// Every logical key starting with `prefix` in ascending order,
+    ///
+    /// without reading the values
+    pub fn scan_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, MdbError> {
+        log::trace!("excecuting lmdb key scan");
+        let reader: ReadonlyTransaction = self.env.get_reader()?;
+        let db: Database = reader.bind(&self.handle);
+        manifest_keys(&db, prefix)
+    }
+    /// Scan like `scan_prefix` without opening sealed values
+    pub(crate) fn scan_prefix_raw(
+        e: &Environment,
+        h: &DbHandle,
+        prefix: &[u8],
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, MdbError> {
+        log::trace!("excecuting lmdb scan");
+        let reader: ReadonlyTransaction = e.get_reader()?;
+        let db: Database = reader.bind(h);
+        let keys = manifest_keys(&db, prefix)?;
+        let mut result: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for k in keys {
+            let v = read_chunks(&db, &k)?;
+            result.push((k, v));
+        }
+        Ok(result)
+    }
     /// Deletes a key/value pair from the database
     pub fn delete(e: &Environment, h: &DbHandle, k: &[u8]) -> Result<(), MdbError> {
         info!("excecuting lmdb delete");
@@ -130,60 +282,302 @@ impl DatabaseEnvironment {
             error!("can't delete empty key");
             return Err(MdbError::NotFound);
         }
-        let new_txn = e.new_transaction();
-        let txn = new_txn?;
-        let get_reader = e.get_reader();
-        let reader: ReadonlyTransaction = get_reader?;
-        let db_reader: Database = reader.bind(h);
+        let txn = e.new_transaction()?;
         {
             let db = txn.bind(h);
+            delete_chunks(&db, k)?;
+        }
+        txn.commit()
+    }
+}
 
-            for num_writes in 0..usize::MAX {
-                let mut new_key: Vec<u8> = k.to_vec();
-                let mut key_count: Vec<u8> = num_writes.to_be_bytes().to_vec();
-                new_key.append(&mut key_count);
-                let r = db_reader.get::<Vec<u8>>(&new_key).unwrap_or_default();
-                if r.is_empty() {
-                    break;
-                }
-                db.del(&new_key)
-                    .unwrap_or_else(|_| error!("failed to delete"));
+/// Record the chunk size of an environment when it is created, from
+///
+/// `IS2FP_CHUNK_SIZE` if present, otherwise derived from available memory.
+///
+/// Existing environments keep their recorded size.
+fn init_chunk_size(e: &Environment, h: &DbHandle, s: &System) -> Result<(), MdbError> {
+    let configured = utils::get_env_parse::<usize>(IS2FP_CHUNK_SIZE).filter(|v| *v > 0);
+    let txn = e.new_transaction()?;
+    {
+        let db: Database = txn.bind(h);
+        match (read_chunk_size(&db), configured) {
+            (Some(recorded), Some(c)) if c != recorded => {
+                warn!("{} only applies to new environments, keeping chunk size {}", IS2FP_CHUNK_SIZE, recorded);
+            }
+            (Some(_), _) => (),
+            (None, configured) => {
+                let chunk_size = configured.unwrap_or_else(|| {
+                    ((s.available_memory() as f32 * CHUNK_SIZE_MEMORY_RATIO) as usize).max(1)
+                });
+                info!("setting lmdb chunk size to: {}", chunk_size);
+                let v: Vec<u8> = (chunk_size as u64).to_be_bytes().to_vec();
+                db.set(&CHUNK_SIZE_KEY.as_bytes().to_vec(), &v)?;
             }
         }
-        txn.commit()
     }
+    txn.commit()
 }
 
-/// Write chunks to the database. This function uses one percent
-///
-/// of the map size . Setting the map_size to a low value
-///
-/// will cause degraded performance.
-pub fn write_chunks(e: &Environment, h: &DbHandle, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
-    let s = System::new_all();
-    let chunk_size = (s.available_memory() as f32 * CHUNK_SIZE_MEMORY_RATIO) as usize;
-    let mut writes: usize = 1;
-    let mut index: usize = 0;
-    let length = v.len();
-    loop {
-        let mut old_key: Vec<u8> = k.to_vec();
-        let mut append: Vec<u8> = (writes - 1).to_be_bytes().to_vec();
-        old_key.append(&mut append);
-        if length > chunk_size && (length - index > chunk_size) {
-            // write chunks until the last value which is smaller than chunk_size
-            let _ = DatabaseEnvironment::write(
-                e,
-                h,
-                &old_key,
-                &v[index..(chunk_size * writes)].to_vec(),
-            );
-            index += chunk_size;
-            writes += 1;
-        } else {
-            DatabaseEnvironment::write(e, h, &old_key, &v[index..length].to_vec())?;
-            return Ok(());
+/// Chunk size recorded for the environment
+fn read_chunk_size(db: &Database) -> Option<usize> {
+    let v = db.get::<Vec<u8>>(&CHUNK_SIZE_KEY.as_bytes().to_vec()).ok()?;
+    let bytes: [u8; 8] = v.as_slice().try_into().ok()?;
+    Some(u64::from_be_bytes(bytes) as usize).filter(|c| *c > 0)
+}
+
+/// Key of the `n`th chunk of a logical key
+fn chunk_key(k: &[u8], n: usize) -> Vec<u8> {
+    let mut new_key: Vec<u8> = k.to_vec();
+    let mut key_count: Vec<u8> = n.to_be_bytes().to_vec();
+    new_key.append(&mut key_count);
+    new_key
+}
+
+/// Key of the chunk manifest of a logical key
+fn manifest_key(k: &[u8]) -> Vec<u8> {
+    let mut new_key: Vec<u8> = MANIFEST_PREFIX.to_vec();
+    new_key.extend_from_slice(k);
+    new_key
+}
+
+/// Logical keys starting with `prefix`. Every value has a manifest, so
+///
+/// the manifests list the logical keys. The range never starts at a
+///
+/// zero-length key, which LMDB can't position a cursor on.
+fn manifest_keys(db: &Database, prefix: &[u8]) -> Result<Vec<Vec<u8>>, MdbError> {
+    let start = manifest_key(prefix);
+    match db.keyrange_from(&start) {
+        Ok(iter) => Ok(iter
+            .map(|c| c.get_key())
+            .take_while(|k: &Vec<u8>| k.starts_with(&start))
+            .map(|k: Vec<u8>| k[MANIFEST_PREFIX.len()..].to_vec())
+            .collect()),
+        Err(MdbError::NotFound) => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The chunk manifest holds the number of chunks of the last
+///
+/// committed value.
+fn read_manifest(db: &Database, k: &[u8]) -> Option<usize> {
+    let manifest = db.get::<Vec<u8>>(&manifest_key(k)).ok()?;
+    let bytes: [u8; 8] = manifest.as_slice().try_into().ok()?;
+    Some(u64::from_be_bytes(bytes) as usize)
+}
+
+/// Reassemble the chunks of a logical key
+fn read_chunks(db: &Database, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+    let mut result: Vec<u8> = Vec::new();
+    let Some(chunks) = read_manifest(db, k) else {
+        return Ok(result);
+    };
+    for num_writes in 0..chunks {
+        let mut r = db.get::<Vec<u8>>(&chunk_key(k, num_writes))
+            .map_err(|_| {
+                error!("missing chunk {} of {} for key {:?}", num_writes, chunks, k);
+                MdbError::Corrupted
+            })?;
+        result.append(&mut r);
+    }
+    Ok(result)
+}
+
+/// Remove every chunk and the manifest of a logical key
+fn delete_chunks(db: &Database, k: &[u8]) -> Result<(), MdbError> {
+    let Some(chunks) = read_manifest(db, k) else {
+        return Ok(());
+    };
+    for num_writes in 0..chunks {
+        match db.del(&chunk_key(k, num_writes)) {
+            Ok(_) | Err(MdbError::NotFound) => (),
+            Err(e) => return Err(e),
         }
     }
+    db.del(&manifest_key(k))
+}
+
+/// Write chunks to the database. Chunks use the size recorded for the
+///
+/// environment at creation, one percent of the map size by default.
+///
+/// Setting the map_size to a low value will cause degraded performance.
+///
+/// The previous value is replaced in the same transaction as the new
+///
+/// chunks and their manifest, so a failed write leaves the last committed
+///
+/// value intact.
+///
+/// Values are sealed when the database is encrypted.
+pub fn write_chunks(db: &DatabaseEnvironment, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+    let sealed = crypto::seal(db.sealer.as_ref(), k, v)?;
+    write_raw_chunks(&db.env, &db.handle, k, &sealed)
+}
+
+/// Write several values in one transaction, either all of them are
+///
+/// committed or none. Values are sealed when the database is encrypted.
+pub fn write_batch(db: &DatabaseEnvironment, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), MdbError> {
+    let sealed = entries
+        .iter()
+        .map(|(k, v)| crypto::seal(db.sealer.as_ref(), k, v).map(|v| (k, v)))
+        .collect::<Result<Vec<_>, MdbError>>()?;
+    info!("excecuting lmdb batch write of {} keys", sealed.len());
+    let txn = db.env.new_transaction()?;
+    {
+        let db: Database = txn.bind(&db.handle);
+        for (k, v) in sealed {
+            set_chunks(&db, k, &v)?;
+        }
+    }
+    txn.commit()
+}
+
+/// Write chunks to the database without sealing the value
+pub(crate) fn write_raw_chunks(
+    e: &Environment,
+    h: &DbHandle,
+    k: &[u8],
+    v: &[u8],
+) -> Result<(), MdbError> {
+    info!("excecuting lmdb write");
+    let txn = e.new_transaction()?;
+    {
+        let db: Database = txn.bind(h);
+        set_chunks(&db, k, v)?;
+    }
+    txn.commit()
+}
+
+/// Replace the chunks and manifest of a logical key
+fn set_chunks(db: &Database, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+    if k.is_empty() {
+        error!("can't write empty key");
+        return Err(MdbError::NotFound);
+    }
+    if k[0] == 0 {
+        error!("keys starting with a null byte are reserved");
+        return Err(MdbError::StateError(String::from("reserved key")));
+    }
+    let chunk_size = read_chunk_size(db).unwrap_or(DEFAULT_CHUNK_SIZE);
+    delete_chunks(db, k)?;
+    let mut chunks: usize = 0;
+    for chunk in v.chunks(chunk_size) {
+        db.set(&chunk_key(k, chunks), &chunk.to_vec())?;
+        chunks += 1;
+    }
+    if chunks == 0 {
+        // empty values are stored as a single empty chunk
+        db.set(&chunk_key(k, 0), &Vec::<u8>::new())?;
+        chunks = 1;
+    }
+    let manifest: Vec<u8> = (chunks as u64).to_be_bytes().to_vec();
+    db.set(&manifest_key(k), &manifest)
+}
+
+/// Whether chunk manifests are stored under `MANIFEST_PREFIX`
+fn has_manifest_layout(e: &Environment, h: &DbHandle) -> Result<bool, MdbError> {
+    let reader: ReadonlyTransaction = e.get_reader()?;
+    let db: Database = reader.bind(h);
+    match db.get::<Vec<u8>>(&MANIFEST_LAYOUT_KEY.as_bytes().to_vec()) {
+        Ok(_) => Ok(true),
+        Err(MdbError::NotFound) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Older environments stored manifests under the logical key itself,
+///
+/// or no manifest at all. Move them under `MANIFEST_PREFIX` once, so
+///
+/// scans can rely on manifests. Logical keys are recognized by their
+///
+/// first chunk, which is only safe for keys written by the old layout.
+pub(crate) fn upgrade_manifests(e: &Environment, h: &DbHandle) -> Result<(), MdbError> {
+    if has_manifest_layout(e, h)? {
+        return Ok(());
+    }
+    let first_chunk: Vec<u8> = 0usize.to_be_bytes().to_vec();
+    let txn = e.new_transaction()?;
+    {
+        let db: Database = txn.bind(h);
+        let raw_keys: Vec<Vec<u8>> = match db.iter() {
+            Ok(iter) => iter.map(|c| c.get_key()).collect(),
+            Err(MdbError::NotFound) => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        let mut moved: usize = 0;
+        for raw_key in raw_keys {
+            if raw_key.starts_with(MANIFEST_PREFIX)
+                || raw_key.len() <= first_chunk.len()
+                || !raw_key.ends_with(&first_chunk)
+            {
+                continue;
+            }
+            let k = &raw_key[..raw_key.len() - first_chunk.len()];
+            if read_manifest(&db, k).is_some() {
+                continue;
+            }
+            let legacy = db
+                .get::<Vec<u8>>(&k.to_vec())
+                .ok()
+                .and_then(|v| <[u8; 8]>::try_from(v.as_slice()).ok())
+                .map(|b| u64::from_be_bytes(b) as usize)
+                .filter(|c| *c > 0 && db.get::<Vec<u8>>(&chunk_key(k, c - 1)).is_ok());
+            let chunks = match legacy {
+                Some(chunks) => {
+                    db.del(&k.to_vec())?;
+                    chunks
+                }
+                // values written before chunk manifests existed
+                None => (1..usize::MAX)
+                    .find(|n| db.get::<Vec<u8>>(&chunk_key(k, *n)).map(|r| r.is_empty()).unwrap_or(true))
+                    .unwrap_or(1),
+            };
+            db.set(&manifest_key(k), &(chunks as u64).to_be_bytes().to_vec())?;
+            moved += 1;
+        }
+        if moved > 0 {
+            info!("moved {} chunk manifests", moved);
+        }
+        db.set(&MANIFEST_LAYOUT_KEY.as_bytes().to_vec(), &vec![1u8])?;
+    }
+    txn.commit()
+}
+
+/// Environment in a temporary directory that is removed on drop
+#[cfg(test)]
+pub(crate) struct TempEnvironment {
+    db: DatabaseEnvironment,
+    path: PathBuf,
+}
+
+#[cfg(test)]
+impl TempEnvironment {
+    /// Open a fresh environment under the system temporary directory
+    pub(crate) fn open() -> Result<Self, MdbError> {
+        let path = std::env::temp_dir().join(format!("is2fp-test-{}", rand::random::<u64>()));
+        let db = DatabaseEnvironment::open_at(&path)?;
+        Ok(TempEnvironment { db, path })
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempEnvironment {
+    type Target = DatabaseEnvironment;
+    fn deref(&self) -> &DatabaseEnvironment {
+        &self.db
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempEnvironment {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
 }
 
 // Tests
@@ -197,16 +591,161 @@ mod tests {
 
     #[test]
     fn environment_test() -> Result<(), MdbError> {
-        let db = &DATABASE_LOCK;
+        let db = TempEnvironment::open()?;
         const DATA_SIZE_10MB: usize = 10000000;
         let mut data = vec![0u8; DATA_SIZE_10MB];
         rand::rng().fill_bytes(&mut data);
         let k = "test-key".as_bytes();
         let expected = &data.to_vec();
-        write_chunks(&db.env, &db.handle, &Vec::from(k), &Vec::from(data))?;
-        let actual = DatabaseEnvironment::read(&db.env, &db.handle, &Vec::from(k));
+        write_chunks(&db, &Vec::from(k), &Vec::from(data))?;
+        let actual = db.read(&Vec::from(k));
         assert_eq!(expected.to_vec(), actual?);
         let _ = DatabaseEnvironment::delete(&db.env, &db.handle, &Vec::from(k))?;
         Ok(())
     }
+
+    #[test]
+    fn overwrite_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        const DATA_SIZE_10MB: usize = 10000000;
+        const DATA_SIZE_1KB: usize = 1000;
+        let mut large = vec![0u8; DATA_SIZE_10MB];
+        rand::rng().fill_bytes(&mut large);
+        let mut small = vec![0u8; DATA_SIZE_1KB];
+        rand::rng().fill_bytes(&mut small);
+        let k = "test-overwrite-key".as_bytes();
+        write_chunks(&db, k, &large)?;
+        write_chunks(&db, k, &small)?;
+        let actual = db.read(&Vec::from(k))?;
+        assert_eq!(small, actual);
+        DatabaseEnvironment::delete(&db.env, &db.handle, k)?;
+        let actual = db.read(&Vec::from(k))?;
+        assert!(actual.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn write_batch_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        let first = "test-batch-first".as_bytes().to_vec();
+        let second = "test-batch-second".as_bytes().to_vec();
+        write_batch(&db, &[(first.clone(), vec![1]), (second.clone(), vec![2])])?;
+        assert_eq!(vec![1], db.read(&first)?);
+        assert_eq!(vec![2], db.read(&second)?);
+        // a reserved key fails the whole batch
+        let reserved = vec![0u8, 1];
+        assert!(write_batch(&db, &[(first.clone(), vec![3]), (reserved, vec![4])]).is_err());
+        assert_eq!(vec![1], db.read(&first)?);
+        Ok(())
+    }
+
+    #[test]
+    fn sealer_test() -> Result<(), MdbError> {
+        // each environment keeps its own key
+        let mut sealed = TempEnvironment::open()?;
+        let plain = TempEnvironment::open()?;
+        let sealer = crypto::setup(&sealed.env, &sealed.handle, "correct horse")
+            .map_err(|_| MdbError::Corrupted)?;
+        sealed.db.sealer = Some(sealer);
+        let k = "test-sealer-key".as_bytes();
+        write_chunks(&sealed, k, k)?;
+        write_chunks(&plain, k, k)?;
+        assert_ne!(k.to_vec(), DatabaseEnvironment::read_raw(&sealed.env, &sealed.handle, k)?);
+        assert_eq!(k.to_vec(), DatabaseEnvironment::read_raw(&plain.env, &plain.handle, k)?);
+        assert_eq!(k.to_vec(), sealed.read(k)?);
+        assert_eq!(k.to_vec(), plain.read(k)?);
+        Ok(())
+    }
+
+    #[test]
+    fn chunk_size_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        let txn = db.env.new_transaction()?;
+        {
+            let d: Database = txn.bind(&db.handle);
+            d.set(&CHUNK_SIZE_KEY.as_bytes().to_vec(), &4u64.to_be_bytes().to_vec())?;
+        }
+        txn.commit()?;
+        let k = "test-chunk-key".as_bytes();
+        write_chunks(&db, k, &[1u8; 10])?;
+        let reader = db.env.get_reader()?;
+        let d: Database = reader.bind(&db.handle);
+        assert_eq!(Some(3), read_manifest(&d, k));
+        assert_eq!(vec![1u8; 10], read_chunks(&d, k)?);
+        Ok(())
+    }
+
+    #[test]
+    fn manifest_collision_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        // the first chunk of one key is the bare name of the other
+        let k = "test-collide".as_bytes();
+        let other = chunk_key(k, 0);
+        write_chunks(&db, k, &[1u8; 3])?;
+        write_chunks(&db, &other, &[2u8; 5])?;
+        assert_eq!(vec![1u8; 3], db.read(&k.to_vec())?);
+        assert_eq!(vec![2u8; 5], db.read(&other)?);
+        let actual = db.scan_prefix(k)?;
+        assert_eq!(vec![k.to_vec(), other], actual.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        assert!(write_chunks(&db, &manifest_key(k), &[1u8]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn readonly_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("is2fp-test-{}", rand::random::<u64>()));
+        assert!(DatabaseEnvironment::open_at_readonly(&path).is_err());
+        let k = "test-readonly-key".as_bytes();
+        {
+            let db = DatabaseEnvironment::open_at(&path)?;
+            write_chunks(&db, k, k)?;
+        }
+        let db = DatabaseEnvironment::open_at_readonly(&path)?;
+        let actual = db.read(&k.to_vec());
+        let write = write_chunks(&db, k, &[1u8]);
+        drop(db);
+        let _ = std::fs::remove_dir_all(&path);
+        assert_eq!(k.to_vec(), actual?);
+        assert!(write.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scan_prefix_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        let keys = ["test-scan-a", "test-scan-b", "test-scan-c"];
+        for k in keys {
+            write_chunks(&db, k.as_bytes(), k.as_bytes())?;
+        }
+        write_chunks(&db, "test-scanx".as_bytes(), &[1u8])?;
+        let actual = db.scan_prefix("test-scan-".as_bytes())?;
+        let keys = db.scan_keys("test-scan-".as_bytes())?;
+        assert_eq!(actual.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>(), keys);
+        let expected: Vec<(Vec<u8>, Vec<u8>)> = keys
+            .iter()
+            .map(|k| (k.as_bytes().to_vec(), k.as_bytes().to_vec()))
+            .collect();
+        assert_eq!(expected, actual);
+        for k in keys {
+            DatabaseEnvironment::delete(&db.env, &db.handle, k.as_bytes())?;
+        }
+        DatabaseEnvironment::delete(&db.env, &db.handle, "test-scanx".as_bytes())?;
+        Ok(())
+    }
+
+    #[test]
+    fn scan_all_test() -> Result<(), MdbError> {
+        let db = TempEnvironment::open()?;
+        let keys = ["test-all-a", "test-all-b"];
+        for k in keys {
+            write_chunks(&db, k.as_bytes(), k.as_bytes())?;
+        }
+        // an empty prefix returns every logical key
+        let actual = db.scan_prefix(&[])?;
+        for k in keys {
+            assert!(actual.contains(&(k.as_bytes().to_vec(), k.as_bytes().to_vec())));
+        }
+        assert!(actual.iter().any(|(k, _)| k == migration::SCHEMA_VERSION_KEY.as_bytes()));
+        Ok(())
+    }
 }
@@ -6,10 +6,16 @@ use serde::{Deserialize, Serialize};
 #[derive(Debug, Error)]
 #[error("ip2p error. See logs for more info.")]
 pub enum Ip2pError {
+    Backup,
+    Crypto,
     Database(MdbError),
+    Decode(bincode::Error),
+    Encode(bincode::Error),
     I2P,
+    Identity,
     J4I2PRS,
     Message,
+    Passphrase,
     PowError,
     Relay,
     RocketError(rocket::Error),
@@ -0,0 +1,279 @@
+#![deny(missing_docs)]
+
+//! Tunnel health supervisor.
+//!
+//! Periodically checks the router and the http proxy, then sends a probe
+//!
+//! through the proxy to our own relay server. Failed tunnels mark the node
+//!
+//! `Degraded` and are restarted, backing off while they keep failing.
+
+use crate::{
+    backend::{
+        Destination,
+        SharedBackend,
+    },
+    error as is2fp_error,
+    i2p,
+    rotation,
+    storage::{
+        SharedStorage,
+        Storage,
+    },
+    store::{
+        Namespace,
+        Store,
+    },
+    utils,
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Environment variable for the seconds between health checks
+pub const IS2FP_HEALTH_INTERVAL: &str = "IS2FP_HEALTH_INTERVAL";
+/// Environment variable for the longest wait between restarts in seconds
+pub const IS2FP_HEALTH_MAX_BACKOFF: &str = "IS2FP_HEALTH_MAX_BACKOFF";
+/// Path of the probe route on the relay server
+pub const PROBE_PATH: &str = "/i2p/probe";
+/// Default seconds between health checks
+const DEFAULT_HEALTH_INTERVAL: u64 = 120;
+/// Default longest wait between restarts
+const DEFAULT_HEALTH_MAX_BACKOFF: u64 = 3600;
+/// Seconds to wait for a probe to come back
+const PROBE_TIMEOUT: u64 = 90;
+
+/// Request sent to our own relay server, echoed back by `/i2p/probe`
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Probe {
+    /// Random value identifying the probe
+    pub nonce: String,
+}
+
+/// Outcome of a health check
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Health {
+    /// The probe made it through the proxy and server tunnel
+    Healthy,
+    /// The router stopped, tunnels can't be rebuilt until it is back
+    RouterDown,
+    /// The http proxy doesn't accept connections
+    ProxyDown,
+    /// The proxy is up but the probe didn't reach the relay server
+    ServerDown,
+}
+
+/// Check interval and restart backoff
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HealthConfig {
+    /// Seconds between health checks
+    pub interval: u64,
+    /// Longest wait between restarts in seconds
+    pub max_backoff: u64,
+}
+
+impl HealthConfig {
+    /// Settings from `IS2FP_HEALTH_INTERVAL` and `IS2FP_HEALTH_MAX_BACKOFF`
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: u64| utils::get_env_parse::<u64>(name).filter(|v| *v > 0).unwrap_or(default);
+        HealthConfig {
+            interval: read(IS2FP_HEALTH_INTERVAL, DEFAULT_HEALTH_INTERVAL),
+            max_backoff: read(IS2FP_HEALTH_MAX_BACKOFF, DEFAULT_HEALTH_MAX_BACKOFF),
+        }
+    }
+    /// Seconds to wait before the next restart after `failures` restarts
+    ///
+    /// that didn't bring the tunnels back. Doubles up to `max_backoff`.
+    fn backoff(&self, failures: u32) -> u64 {
+        self.interval
+            .saturating_mul(1u64 << failures.min(16))
+            .min(self.max_backoff)
+    }
+}
+
+/// Restarts since the tunnels were last healthy
+#[derive(Debug, Default)]
+struct Recovery {
+    /// Restarts that haven't brought the tunnels back yet
+    failures: u32,
+    /// Unix time before which no restart is attempted
+    retry_at: u64,
+}
+
+/// Check the router and proxy, then probe our own relay server through the proxy
+pub async fn probe(s: &dyn Storage, backend: &SharedBackend) -> Result<Health, is2fp_error::Ip2pError> {
+    let b32 = i2p::get_destination(s)?;
+    if b32.is_empty() {
+        error!("no relay destination to probe");
+        return Err(is2fp_error::Ip2pError::I2P);
+    }
+    let checks = backend.clone();
+    let (router, proxy) = tokio::task::spawn_blocking(move || (checks.is_running(), checks.check_proxy()))
+        .await
+        .map_err(|_| is2fp_error::Ip2pError::I2P)?;
+    if !router {
+        return Ok(Health::RouterDown);
+    }
+    if !proxy {
+        return Ok(Health::ProxyDown);
+    }
+    let sent = Probe { nonce: rand::random::<u64>().to_string() };
+    let body = rocket::serde::json::to_string(&sent)
+        .map_err(|_| is2fp_error::Ip2pError::I2P)?
+        .into_bytes();
+    let request = backend.post_json(None, &b32, PROBE_PATH, body);
+    let response = tokio::time::timeout(Duration::from_secs(PROBE_TIMEOUT), request).await;
+    let received = match response {
+        Ok(Ok(response)) => rocket::serde::json::from_slice::<Probe>(&response).ok(),
+        _ => None,
+    };
+    match received {
+        Some(received) if received == sent => Ok(Health::Healthy),
+        _ => Ok(Health::ServerDown),
+    }
+}
+
+/// Restart the tunnels behind `health`. Rotation waits until the
+///
+/// restarted destination is back up.
+async fn restart(s: &dyn Storage, backend: &SharedBackend, health: Health) -> Result<(), is2fp_error::Ip2pError> {
+    let _guard = rotation::DESTINATION_LOCK.lock().await;
+    let sk: Option<String> = Store::get(s, Namespace::Settings, i2p::APP_I2P_SK)?;
+    let dest = Destination { b32: i2p::get_destination(s)?, sk: sk.unwrap_or_default() };
+    let backend = backend.clone();
+    // tunnel calls block until the new tunnels are built
+    tokio::task::spawn_blocking(move || {
+        if health == Health::ProxyDown {
+            info!("restarting http proxy");
+            backend.restart_proxy()?;
+        }
+        info!("restarting server tunnel for {}", &dest.b32);
+        backend.stop_server_tunnel(&dest)?;
+        backend.start_server_tunnel(&dest, utils::get_app_port())
+    })
+    .await
+    .map_err(|_| is2fp_error::Ip2pError::I2P)?
+}
+
+/// Probe once, update the lifecycle and restart failed tunnels when the
+///
+/// backoff allows it
+async fn check(
+    s: &dyn Storage,
+    backend: &SharedBackend,
+    config: &HealthConfig,
+    recovery: &mut Recovery,
+) -> Result<Health, is2fp_error::Ip2pError> {
+    let health = probe(s, backend).await?;
+    if health == Health::Healthy {
+        if recovery.failures > 0 {
+            info!("tunnels recovered");
+        }
+        *recovery = Default::default();
+        i2p::set_status(s, i2p::Lifecycle::Ready)?;
+        return Ok(health);
+    }
+    warn!("i2p health check failed: {:?}", health);
+    i2p::set_status(s, i2p::Lifecycle::Degraded)?;
+    let now = utils::get_unix_time()?;
+    // the router has to come back on its own
+    if health == Health::RouterDown || now < recovery.retry_at {
+        return Ok(health);
+    }
+    restart(s, backend, health)
+        .await
+        .unwrap_or_else(|e| error!("failed to restart tunnels: {:?}", e));
+    recovery.retry_at = now + config.backoff(recovery.failures);
+    recovery.failures = recovery.failures.saturating_add(1);
+    Ok(health)
+}
+
+/// Background health supervisor. Runs until shutdown.
+pub async fn run(s: SharedStorage, backend: SharedBackend, mut shutdown: watch::Receiver<bool>) {
+    let config = HealthConfig::from_env();
+    info!("i2p health checks: {:?}", config);
+    let mut recovery = Recovery::default();
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(config.interval)) => {}
+            _ = shutdown.changed() => return,
+        }
+        // only supervise tunnels that were built
+        let status = i2p::check_connection(s.as_ref()).await.unwrap_or_default();
+        if !matches!(status.state, i2p::Lifecycle::Ready | i2p::Lifecycle::Degraded) {
+            continue;
+        }
+        // restarts block for a while, don't hold up shutdown
+        tokio::select! {
+            r = check(s.as_ref(), &backend, &config, &mut recovery) => {
+                if let Err(e) = r {
+                    error!("i2p health check failed: {:?}", e);
+                }
+            }
+            _ = shutdown.changed() => return,
+        }
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        backend::TestBackend,
+        storage::MemoryStorage,
+    };
+    use std::sync::{
+        atomic::Ordering,
+        Arc,
+    };
+
+    #[test]
+    fn backoff_test() {
+        let config = HealthConfig { interval: 60, max_backoff: 600 };
+        assert_eq!(60, config.backoff(0));
+        assert_eq!(240, config.backoff(2));
+        assert_eq!(600, config.backoff(10));
+        assert_eq!(600, config.backoff(u32::MAX));
+    }
+
+    #[test]
+    fn check_test() -> Result<(), is2fp_error::Ip2pError> {
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        let s = MemoryStorage::default();
+        i2p::reset_status(&s)?;
+        for state in [i2p::Lifecycle::RouterWarming, i2p::Lifecycle::TunnelsBuilding, i2p::Lifecycle::Ready] {
+            i2p::set_status(&s, state)?;
+        }
+        Store::put(&s, Namespace::Settings, i2p::APP_B32_DEST, &String::from("self.b32.i2p"))?;
+        let test_backend = Arc::new(TestBackend::default());
+        let backend: SharedBackend = test_backend.clone();
+        let config = HealthConfig { interval: 60, max_backoff: 600 };
+        let mut recovery = Recovery::default();
+        let state = || i2p::check_connection(&s);
+        runtime.block_on(async {
+            assert_eq!(Health::Healthy, check(&s, &backend, &config, &mut recovery).await?);
+            // a dead proxy degrades the node and is restarted
+            test_backend.offline.store(true, Ordering::SeqCst);
+            assert_eq!(Health::ProxyDown, check(&s, &backend, &config, &mut recovery).await?);
+            assert_eq!(i2p::Lifecycle::Degraded, state().await?.state);
+            assert_eq!(1, recovery.failures);
+            assert!(recovery.retry_at > 0);
+            assert_eq!(vec![String::from("self.b32.i2p")], *test_backend.published.lock().unwrap());
+            assert_eq!(Health::Healthy, check(&s, &backend, &config, &mut recovery).await?);
+            assert_eq!(i2p::Lifecycle::Ready, state().await?.state);
+            assert_eq!(0, recovery.failures);
+            Ok(())
+        })
+    }
+}
@@ -1,6 +1,37 @@
 //! embedded i2p module
+//!
+//! `J4I2prsBackend` embeds the j4-i2p-rs router, see `backend` for the alternatives.
 
-use crate::{utils, db, error as ip2p_error, i2p};
+use crate::{
+    backend::{
+        Destination,
+        I2pBackend,
+        SharedBackend,
+    },
+    proxy::{
+        ProxyScheme,
+        ProxyUrl,
+    },
+    reseed,
+    rotation,
+    router_config,
+    sam,
+    tunnel_config::{
+        TunnelConfig,
+        TunnelOptions,
+    },
+    utils,
+    error as ip2p_error,
+    i2p,
+    storage::{
+        SharedStorage,
+        Storage,
+    },
+    store::{
+        Namespace,
+        Store,
+    },
+};
 /// Environment variable for the application custom port
 pub const IS2FP_PORT:                   &str = "IS2FP_PORT";
 /// Default app port
@@ -20,8 +51,7 @@ pub const APP_I2P_SK:                   &str = "app-i2p-sk";
 /// Override router creation and startup for machines with existing router instances
 pub const IS2FP_ROUTER_OVERRIDE:        &str = "IS2FP_ROUTER_OVERRIDE";
 
-use lazy_static::lazy_static;
-use std::sync::Mutex;
+use futures::future::BoxFuture;
 use j4i2prs::{
     router_wrapper as rw,
     tunnel_control as tc,
@@ -33,143 +63,514 @@ use serde::{
     Serialize,
 };
 use std::{
-    fs::File,
-    io::{
-        self,
-        BufRead,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        mpsc,
+        Arc,
+        Mutex,
     },
-    path::Path,
     thread,
 };
+use tokio::sync::watch;
+
+/// Maximum number of transitions kept in the status history
+const MAX_TRANSITIONS: usize = 32;
+/// Seconds to wait at most for the tunnels of a new client destination
+const CLIENT_WARMUP: u64 = 120;
+/// Seconds between readiness checks of a new client destination
+const CLIENT_READY_POLL: u64 = 2;
+/// Seconds between router thread checks once tunnels are ready
+const ROUTER_CHECK_INTERVAL: u64 = 60;
+/// Seconds between checks while the router warms up
+const ROUTER_WARMUP_POLL: u64 = 5;
+
+/// Router and tunnel lifecycle
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub enum Lifecycle {
+    /// The node started, i2p hasn't been touched yet
+    #[default]
+    Starting,
+    /// Waiting for the router to integrate with the network
+    RouterWarming,
+    /// Router is up, the relay server tunnel is being built
+    TunnelsBuilding,
+    /// The relay server is reachable over i2p
+    Ready,
+    /// Tunnels were ready but the router or a tunnel stopped responding
+    Degraded,
+    /// Shut down on request
+    Stopped,
+    /// Startup failed, with the reason
+    Failed(String),
+}
+
+impl Lifecycle {
+    /// Whether the lifecycle may move from this state to `next`
+    pub fn allows(&self, next: &Lifecycle) -> bool {
+        use Lifecycle::*;
+        match (self, next) {
+            (_, Stopped) | (_, Failed(_)) => !matches!(self, Stopped),
+            (Starting, RouterWarming) => true,
+            (RouterWarming, TunnelsBuilding) => true,
+            (TunnelsBuilding, Ready) => true,
+            (Ready, Degraded) => true,
+            (Degraded, Ready) | (Degraded, TunnelsBuilding) => true,
+            (Stopped, Starting) | (Failed(_), Starting) => true,
+            _ => false,
+        }
+    }
+}
 
-// global
-lazy_static! {
-    /// prevents infinite loop when checking for running router
-    static ref IS_ROUTER_RUNNING: Mutex<bool> = Mutex::new(false);
+/// A lifecycle state and when it was entered
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Transition {
+    /// State entered
+    pub state: Lifecycle,
+    /// Unix time of the transition
+    pub at: u64,
 }
 
-/// https://doc.rust-lang.org/rust-by-example/std_misc/file/read_lines.html
-fn read_lines<P>(filename: P) -> io::Result<io::Lines<io::BufReader<File>>>
-where
-    P: AsRef<Path>,
-{
-    let file = File::open(filename)?;
-    Ok(io::BufReader::new(file).lines())
+/// Current lifecycle state with the most recent transitions, oldest first
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct I2pStatus {
+    /// Current state
+    pub state: Lifecycle,
+    /// Recent transitions including the current state
+    pub transitions: Vec<Transition>,
 }
 
+/// Response of `/i2p/status`
 #[derive(Debug, Deserialize, Serialize)]
 pub struct HttpProxyStatus {
+    /// The relay server is reachable, `Ready` or `Degraded`
     pub open: bool,
+    /// Current lifecycle state
+    pub state: Lifecycle,
+    /// Recent transitions, oldest first
+    pub transitions: Vec<Transition>,
+}
+
+impl From<I2pStatus> for HttpProxyStatus {
+    fn from(status: I2pStatus) -> Self {
+        HttpProxyStatus {
+            open: matches!(status.state, Lifecycle::Ready | Lifecycle::Degraded),
+            state: status.state,
+            transitions: status.transitions,
+        }
+    }
+}
+
+/// Reset the lifecycle to `Starting`, dropping the previous run's history
+pub fn reset_status(s: &dyn Storage) -> Result<(), ip2p_error::Ip2pError> {
+    let state = Lifecycle::Starting;
+    let status = I2pStatus {
+        transitions: vec![Transition { state: state.clone(), at: utils::get_unix_time()? }],
+        state,
+    };
+    Store::put(s, Namespace::Settings, i2p::I2P_STATUS, &status)?;
+    Ok(())
+}
+
+/// Move the lifecycle to `state` and record the transition.
+///
+/// Transitions the state machine doesn't allow are rejected.
+pub fn set_status(s: &dyn Storage, state: Lifecycle) -> Result<(), ip2p_error::Ip2pError> {
+    let mut status: I2pStatus =
+        Store::get(s, Namespace::Settings, i2p::I2P_STATUS)?.unwrap_or_default();
+    if status.state == state {
+        return Ok(());
+    }
+    if !status.state.allows(&state) {
+        warn!("invalid i2p transition from {:?} to {:?}", status.state, state);
+        return Err(ip2p_error::Ip2pError::I2P);
+    }
+    info!("i2p {:?} -> {:?}", status.state, state);
+    status.transitions.push(Transition { state: state.clone(), at: utils::get_unix_time()? });
+    let excess = status.transitions.len().saturating_sub(MAX_TRANSITIONS);
+    status.transitions.drain(..excess);
+    status.state = state.clone();
+    Store::put(s, Namespace::Settings, i2p::I2P_STATUS, &status)?;
+    Ok(())
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-pub enum ProxyStatus {
-    Opening,
-    Open,
+/// This is the `dest` value of the app i2p tunnels
+pub fn get_destination(s: &dyn Storage) -> Result<String, ip2p_error::Ip2pError> {
+    let app_b32_dest: Option<String> = Store::get(s, Namespace::Settings, i2p::APP_B32_DEST)?;
+    Ok(app_b32_dest.unwrap_or_default())
 }
 
-impl ProxyStatus {
-    pub fn value(&self) -> String {
-        match *self {
-            ProxyStatus::Opening => String::from("opening\n"),
-            ProxyStatus::Open => String::from("open\n"),
+/// Read the lifecycle status from LMDB
+pub async fn check_connection(s: &dyn Storage) -> Result<I2pStatus, ip2p_error::Ip2pError> {
+    let status: Option<I2pStatus> = Store::get(s, Namespace::Settings, i2p::I2P_STATUS)?;
+    match status {
+        Some(s) => Ok(s),
+        None => {
+            error!("i2p status not found");
+            Err(ip2p_error::Ip2pError::Database(MdbError::NotFound))
         }
     }
 }
 
-/// Extract i2p port from environment
-fn get_i2p_proxy_port() -> String {
-    let proxy_host = utils::get_i2p_http_proxy();
-    let values = proxy_host.split(":");
-    let mut v: Vec<String> = values.map(String::from).collect();
-    v.remove(2)
+/// Log the external port the embedded router was assigned
+fn log_external_port() {
+    let path = router_config::get_path();
+    match router_config::RouterConfig::load(&path).map(|c| c.udp_port()) {
+        Ok(Some(port)) => {
+            log::info!("router is running on external port = {}", port);
+            log::info!("open this port for better connectivity");
+            log::info!("this port was randomly assigned, keep it private");
+        }
+        _ => log::warn!("no external port in {}", path.display()),
+    }
 }
 
-/// This is the `dest` value of the app i2p tunnels
-pub fn get_destination() -> Result<String, ip2p_error::Ip2pError> {
-    let db = &db::DATABASE_LOCK;
-    let r_app_b32_dest = db::DatabaseEnvironment::read(
-        &db.env,
-        &db.handle,
-        &i2p::APP_B32_DEST.as_bytes().to_vec(),
-    )
-    .map_err(|_| ip2p_error::Ip2pError::Database(MdbError::Panic))?;
-    let app_b32_dest: String = bincode::deserialize(&r_app_b32_dest[..]).unwrap_or_default();
-    Ok(app_b32_dest)
-}
-
-/// Read base 32 destination address from LMDB
-pub async fn check_connection() -> Result<ProxyStatus, ip2p_error::Ip2pError> {
-    let db = &db::DATABASE_LOCK;
-    let r =
-        db::DatabaseEnvironment::read(&db.env, &db.handle, &i2p::I2P_STATUS.as_bytes().to_vec())
-            .map_err(|_| ip2p_error::Ip2pError::Database(MdbError::Panic))?;
-    if r.is_empty() {
-        error!("i2p status not found");
-        return Err(ip2p_error::Ip2pError::Database(MdbError::NotFound));
-    }
-    let result: ProxyStatus = bincode::deserialize(&r[..]).unwrap_or(ProxyStatus::Opening);
-    Ok(result)
-}
-
-/// Create app tunnel if it don't exist yet
-fn create_server_tunnel() -> Result<tc::Tunnel, ip2p_error::Ip2pError> {
-    let port: u16 = utils::get_app_port();
-    let b32_key = i2p::APP_B32_DEST.as_bytes();
-    let sk_key = i2p::APP_I2P_SK.as_bytes();
-    let db = &db::DATABASE_LOCK;
-    let tunnel: tc::Tunnel =
-        tc::Tunnel::new("127.0.0.1".to_string(), port, tc::TunnelType::Server).unwrap_or_default();
-    let b32_dest: String = tunnel.get_destination();
-    log::debug!("destination: {}", &b32_dest);
-    let v_b32_dest = bincode::serialize(&b32_dest).unwrap_or_default();
-    let v_sk = bincode::serialize(&tunnel.get_sk()).unwrap_or_default();
-    db::write_chunks(&db.env, &db.handle, b32_key, &v_b32_dest)
-        .map_err(|_| ip2p_error::Ip2pError::Database(MdbError::Panic))?;
-    db::write_chunks(&db.env, &db.handle, sk_key, &v_sk)
-        .map_err(|_| ip2p_error::Ip2pError::Database(MdbError::Panic))?;
-    Ok(tunnel)
-}
-
-/// helper method for tunnel creation
-fn process_tunnels(http_proxy_port: u16, app_sk: String) {
-    if let Ok(lines) = read_lines("./router.config") {
-        for line in lines.map_while(Result::ok) {
-            if line.contains("i2np.udp.port") {
-                let port = line.split("=").collect::<Vec<&str>>()[1];
-                log::info!("router is running on external port = {}", port);
-                log::info!("open this port for better connectivity");
-                log::info!("this port was randomly assigned, keep it private");
-                //l.is_running = true;
-                *IS_ROUTER_RUNNING.lock().unwrap() = true;
-                // start the http proxy
-                if http_proxy_port != 0 {
-                    let http_proxy: tc::Tunnel = tc::Tunnel::new(
-                        "127.0.0.1".to_string(),
-                        http_proxy_port,
-                        tc::TunnelType::Http,
-                    ).unwrap_or_default();
-                    let _ = http_proxy.start(None);
-                    log::info!("http proxy on port {}", http_proxy.get_port());
+/// Embedded j4-i2p-rs router. With `IS2FP_ROUTER_OVERRIDE` set the router
+///
+/// and http proxy of another instance on this machine are used instead.
+#[derive(Default)]
+pub struct J4I2prsBackend {
+    /// Updated by the router thread
+    running: Arc<AtomicBool>,
+    /// Asks the router thread to shut the router down
+    stop_router: Mutex<Option<mpsc::Sender<()>>>,
+    /// Thread owning the router
+    router_thread: Mutex<Option<thread::JoinHandle<()>>>,
+    /// Outbound proxy, started by the backend unless it is a SOCKS tunnel
+    proxy: ProxyUrl,
+    /// Tunnels started by this backend and the destination they serve,
+    ///
+    /// empty for the shared http proxy and `client-{port}` for client proxies
+    tunnels: Mutex<Vec<(String, tc::Tunnel)>>,
+    /// Length, quantity, variance and backup of the tunnels it builds
+    options: TunnelConfig,
+}
+
+impl J4I2prsBackend {
+    /// Backend with the outbound proxy from `I2P_PROXY_HOST` and the
+    ///
+    /// tunnel options from `IS2FP_SERVER_TUNNEL` and `IS2FP_CLIENT_TUNNEL`
+    pub fn from_env() -> Result<Self, ip2p_error::Ip2pError> {
+        Ok(J4I2prsBackend {
+            proxy: ProxyUrl::from_env()?,
+            options: TunnelConfig::from_env()?,
+            ..Default::default()
+        })
+    }
+    /// Local tunnel on `port`, its i2cp session built with `options`
+    fn new_tunnel(
+        &self,
+        port: u16,
+        tunnel_type: tc::TunnelType,
+        options: &TunnelOptions,
+    ) -> Result<tc::Tunnel, ip2p_error::Ip2pError> {
+        tc::Tunnel::new_with_options("127.0.0.1".to_string(), port, tunnel_type, options.to_i2cp())
+            .map_err(|_| ip2p_error::Ip2pError::J4I2PRS)
+    }
+    /// Start the shared http proxy on the `I2P_PROXY_HOST` port
+    fn start_http_proxy(&self) -> Result<(), ip2p_error::Ip2pError> {
+        if self.proxy.scheme == ProxyScheme::Socks5 {
+            log::info!("relaying through the SOCKS tunnel at {}", self.proxy);
+            return Ok(());
+        }
+        let http_proxy = self.new_tunnel(self.proxy.port, tc::TunnelType::Http, &self.options.client)?;
+        let _ = http_proxy.start(None);
+        log::info!("http proxy on port {}", http_proxy.get_port());
+        self.keep("", http_proxy)
+    }
+    /// Whether the client proxy on `port` reaches our own relay server.
+    ///
+    /// The proxy accepts connections before its tunnels are built and answers
+    ///
+    /// with an error page until then. Without a relay server to reach only
+    ///
+    /// the proxy port is checked.
+    fn client_ready(&self, port: u16) -> bool {
+        use std::io::{
+            BufRead,
+            Write,
+        };
+        let b32 = self.tunnels.lock().ok().and_then(|tunnels| {
+            tunnels
+                .iter()
+                .map(|(key, _)| key.clone())
+                .find(|key| !key.is_empty() && !key.starts_with(&client_key("")))
+        });
+        let timeout = std::time::Duration::from_secs(CLIENT_READY_POLL * 5);
+        let address = std::net::SocketAddr::from(([127, 0, 0, 1], port));
+        let Ok(mut stream) = std::net::TcpStream::connect_timeout(&address, timeout) else {
+            return false;
+        };
+        let Some(b32) = b32 else {
+            return true;
+        };
+        let _ = stream.set_read_timeout(Some(timeout));
+        let request = format!(
+            "GET http://{b32}/i2p/status HTTP/1.1\r\nHost: {b32}\r\nConnection: close\r\n\r\n"
+        );
+        if stream.write_all(request.as_bytes()).is_err() {
+            return false;
+        }
+        let mut status = String::new();
+        let _ = std::io::BufReader::new(stream).read_line(&mut status);
+        status.split_whitespace().nth(1) == Some("200")
+    }
+    fn keep(&self, b32: &str, tunnel: tc::Tunnel) -> Result<(), ip2p_error::Ip2pError> {
+        self.tunnels
+            .lock()
+            .map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?
+            .push((String::from(b32), tunnel));
+        Ok(())
+    }
+}
+
+impl I2pBackend for J4I2prsBackend {
+    fn name(&self) -> &'static str {
+        "j4i2prs"
+    }
+    fn start_router(&self) -> Result<(), ip2p_error::Ip2pError> {
+        let router_override_disabled = std::env::var(IS2FP_ROUTER_OVERRIDE)
+            .unwrap_or("".to_string()).is_empty();
+        if !router_override_disabled {
+            // don't try to create multiple http proxy tunnels
+            log::info!("using existing router");
+            self.running.store(true, Ordering::SeqCst);
+            return Ok(());
+        }
+        log::info!("starting j4i2prs...");
+        router_config::apply_env()?;
+        reseed::prepare()?;
+        let (tx, rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+        *self.stop_router.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)? = Some(stop_tx);
+        let running = self.running.clone();
+        // the router lives as long as this thread
+        let router_thread = thread::spawn(move || {
+            let router = match rw::Wrapper::create_router() {
+                Ok(router) => router,
+                Err(_) => {
+                    let _ = tx.send(Err(ip2p_error::Ip2pError::J4I2PRS));
+                    return;
+                }
+            };
+            std::thread::sleep(std::time::Duration::from_secs(10));
+            log::info!("starting router");
+            router.invoke_router(rw::METHOD_RUN)
+                .unwrap_or_else(|_| log::error!("failed to run router"));
+            let poll = std::time::Duration::from_secs(ROUTER_WARMUP_POLL);
+            let mut polls: u64 = 0;
+            while !router.is_running().unwrap_or_default() {
+                if polls % (ROUTER_CHECK_INTERVAL / ROUTER_WARMUP_POLL) == 0 {
+                    log::info!("router is warming up, please wait...");
                 }
-                if app_sk.is_empty() {
-                    let t = create_server_tunnel().unwrap_or_default();
-                    let _ = t.start(None);
-                } else {
-                    let app_tunnel = tc::Tunnel::new(
-                        "127.0.0.1".to_string(),
-                        utils::get_app_port(),
-                        tc::TunnelType::ExistingServer,
-                    ).unwrap_or_default();
-                    let _ = app_tunnel.start(Some(String::from(&app_sk)));
+                polls += 1;
+                // shutdown may be requested before the router is up
+                if !matches!(stop_rx.recv_timeout(poll), Err(mpsc::RecvTimeoutError::Timeout)) {
+                    router.invoke_router(rw::METHOD_SHUTDOWN)
+                        .unwrap_or_else(|_| log::error!("failed to stop router"));
+                    let _ = tx.send(Err(ip2p_error::Ip2pError::J4I2PRS));
+                    return;
                 }
-                    let db = &db::DATABASE_LOCK;
-                    let v = bincode::serialize(&ProxyStatus::Open).unwrap_or_default();
-                    db::write_chunks(&db.env, &db.handle, i2p::I2P_STATUS.as_bytes(), &v)
-                        .unwrap_or_else(|_| log::error!("failed to write i2p status."));
             }
+            running.store(true, Ordering::SeqCst);
+            let _ = tx.send(Ok(()));
+            let interval = std::time::Duration::from_secs(ROUTER_CHECK_INTERVAL);
+            while let Err(mpsc::RecvTimeoutError::Timeout) = stop_rx.recv_timeout(interval) {
+                running.store(router.is_running().unwrap_or_default(), Ordering::SeqCst);
+            }
+            log::info!("stopping router");
+            router.invoke_router(rw::METHOD_SHUTDOWN)
+                .unwrap_or_else(|_| log::error!("failed to stop router"));
+            running.store(false, Ordering::SeqCst);
+        });
+        *self.router_thread.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)? = Some(router_thread);
+        rx.recv().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)??;
+        log_external_port();
+        self.start_http_proxy()
+    }
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+    fn create_destination(&self) -> Result<Destination, ip2p_error::Ip2pError> {
+        let tunnel = self.new_tunnel(utils::get_app_port(), tc::TunnelType::Server, &self.options.server)?;
+        Ok(Destination { b32: tunnel.get_destination(), sk: tunnel.get_sk() })
+    }
+    fn check_destination(&self, dest: &Destination) -> Result<(), ip2p_error::Ip2pError> {
+        // the router hands out its private key files in I2P base 64
+        let b32 = sam::private_key_b32(&dest.sk)?;
+        if b32 != dest.b32 {
+            error!("secret key belongs to {}, not {}", b32, dest.b32);
+            return Err(ip2p_error::Ip2pError::J4I2PRS);
+        }
+        Ok(())
+    }
+    fn start_server_tunnel(&self, dest: &Destination, port: u16) -> Result<(), ip2p_error::Ip2pError> {
+        let app_tunnel = self.new_tunnel(port, tc::TunnelType::ExistingServer, &self.options.server)?;
+        let _ = app_tunnel.start(Some(String::from(&dest.sk)));
+        self.keep(&dest.b32, app_tunnel)
+    }
+    fn stop_server_tunnel(&self, dest: &Destination) -> Result<(), ip2p_error::Ip2pError> {
+        let mut tunnels = self.tunnels.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?;
+        for (_, tunnel) in tunnels.iter().filter(|(b32, _)| b32 == &dest.b32) {
+            let _ = tunnel.stop();
+        }
+        tunnels.retain(|(b32, _)| b32 != &dest.b32);
+        Ok(())
+    }
+    fn check_proxy(&self) -> bool {
+        let host = self.proxy.host.trim_start_matches('[').trim_end_matches(']');
+        let address = std::net::ToSocketAddrs::to_socket_addrs(&(host, self.proxy.port))
+            .ok()
+            .and_then(|mut a| a.next());
+        address.is_some_and(|a| {
+            std::net::TcpStream::connect_timeout(&a, std::time::Duration::from_secs(5)).is_ok()
+        })
+    }
+    fn restart_proxy(&self) -> Result<(), ip2p_error::Ip2pError> {
+        if self.stop_router.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?.is_none() {
+            log::warn!("http proxy belongs to the existing router, not restarting it");
+            return Ok(());
+        }
+        let mut tunnels = self.tunnels.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?;
+        for (_, tunnel) in tunnels.iter().filter(|(key, _)| key.is_empty()) {
+            let _ = tunnel.stop();
+        }
+        tunnels.retain(|(key, _)| !key.is_empty());
+        drop(tunnels);
+        self.start_http_proxy()
+    }
+    fn stop(&self) -> Result<(), ip2p_error::Ip2pError> {
+        let mut tunnels = self.tunnels.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?;
+        for (_, tunnel) in tunnels.drain(..) {
+            let _ = tunnel.stop();
+        }
+        // a router started elsewhere is left running
+        let stop_router = self.stop_router.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?.take();
+        if let Some(stop_router) = stop_router {
+            let _ = stop_router.send(());
+        }
+        let router_thread = self.router_thread.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?.take();
+        if let Some(router_thread) = router_thread {
+            router_thread.join().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?;
         }
+        Ok(())
+    }
+    fn open_client(&self) -> Result<String, ip2p_error::Ip2pError> {
+        // every http proxy tunnel has its own client destination
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?
+            .port();
+        let proxy = self.new_tunnel(port, tc::TunnelType::Http, &self.options.client)?;
+        let _ = proxy.start(None);
+        let client = port.to_string();
+        self.keep(&client_key(&client), proxy)?;
+        // the new tunnels take a while to build
+        let poll = std::time::Duration::from_secs(CLIENT_READY_POLL);
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(CLIENT_WARMUP);
+        while !self.client_ready(port) {
+            if std::time::Instant::now() >= deadline {
+                log::error!("client destination on port {} not ready after {}s", port, CLIENT_WARMUP);
+                self.close_client(&client)?;
+                return Err(ip2p_error::Ip2pError::J4I2PRS);
+            }
+            thread::sleep(poll);
+        }
+        Ok(client)
+    }
+    fn close_client(&self, client: &str) -> Result<(), ip2p_error::Ip2pError> {
+        let key = client_key(client);
+        let mut tunnels = self.tunnels.lock().map_err(|_| ip2p_error::Ip2pError::J4I2PRS)?;
+        for (_, tunnel) in tunnels.iter().filter(|(k, _)| k == &key) {
+            let _ = tunnel.stop();
+        }
+        tunnels.retain(|(k, _)| k != &key);
+        Ok(())
+    }
+    fn post_json<'a>(
+        &'a self,
+        client: Option<&'a str>,
+        b32: &'a str,
+        path: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, ip2p_error::Ip2pError>> {
+        Box::pin(async move {
+            // pass the request through the i2p http proxy or SOCKS tunnel
+            let proxy = match client {
+                Some(port) => {
+                    let port = port.parse::<u16>().map_err(|_| ip2p_error::Ip2pError::Relay)?;
+                    ProxyUrl { port, ..Default::default() }
+                }
+                None => self.proxy.clone(),
+            };
+            log::debug!("setting i2p proxy to: {}", &proxy);
+            let proxy = proxy.to_reqwest()?;
+            let client = reqwest::Client::builder().proxy(proxy).build()
+                .map_err(|_| ip2p_error::Ip2pError::Relay)?;
+            let response = client
+                .post(format!("http://{}{}", b32, path))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| {
+                    error!("failed to relay due to: {:?}", e);
+                    ip2p_error::Ip2pError::Relay
+                })?;
+            let bytes = response.bytes().await.map_err(|_| ip2p_error::Ip2pError::Relay)?;
+            Ok(bytes.to_vec())
+        })
+    }
+}
+
+/// Key of a client http proxy in `J4I2prsBackend::tunnels`
+fn client_key(client: &str) -> String {
+    format!("client-{}", client)
+}
+
+/// Load the relay destination, creating one on first start
+fn get_or_create_destination(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+) -> Result<Destination, ip2p_error::Ip2pError> {
+    let app_sk: String = Store::get(s, Namespace::Settings, i2p::APP_I2P_SK)?.unwrap_or_default();
+    if !app_sk.is_empty() {
+        return Ok(Destination { b32: get_destination(s)?, sk: app_sk });
     }
+    let dest = backend.create_destination()?;
+    log::debug!("destination: {}", &dest.b32);
+    let entries = vec![
+        Store::entry(Namespace::Settings, i2p::APP_B32_DEST, &dest.b32)?,
+        Store::entry(Namespace::Settings, i2p::APP_I2P_SK, &dest.sk)?,
+        Store::entry(Namespace::Settings, rotation::APP_DEST_CREATED, &utils::get_unix_time()?)?,
+    ];
+    Store::put_all(s, &entries)?;
+    Ok(dest)
+}
+
+/// Record the transition to `state` and signal it on `tx`
+fn report(s: &dyn Storage, tx: &watch::Sender<Lifecycle>, state: Lifecycle) -> Result<(), ip2p_error::Ip2pError> {
+    set_status(s, state.clone())?;
+    tx.send_replace(state);
+    Ok(())
+}
+
+/// Start the router, then publish the relay server on its destination
+fn run(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+    tx: &watch::Sender<Lifecycle>,
+) -> Result<(), ip2p_error::Ip2pError> {
+    report(s, tx, Lifecycle::RouterWarming)?;
+    backend.start_router()?;
+    report(s, tx, Lifecycle::TunnelsBuilding)?;
+    let dest = get_or_create_destination(s, backend)?;
+    backend.start_server_tunnel(&dest, utils::get_app_port())?;
+    // destinations replaced shortly before a restart keep accepting
+    rotation::restore(s, backend, &rotation::RotationConfig::from_env())?;
+    report(s, tx, Lifecycle::Ready)
 }
 
 /// Start router and automatic i2p tunnel creation
@@ -177,54 +578,88 @@ fn process_tunnels(http_proxy_port: u16, app_sk: String) {
 /// We'll check for an existing i2p secret key. If it doesn't
 ///
 /// exist create a new one.
-pub fn start() -> Result<(), ip2p_error::Ip2pError> {
-    let http_proxy_port: u16 = get_i2p_proxy_port()
-        .parse::<u16>()
-        .unwrap_or(DEFAULT_HTTP_PROXY_PORT);
-    // check for existing app and anon inbound server tunnels
-    let db = &db::DATABASE_LOCK;
-    let r_app_sk =
-        db::DatabaseEnvironment::read(&db.env, &db.handle, &i2p::APP_I2P_SK.as_bytes().to_vec())
-            .map_err(|_| ip2p_error::Ip2pError::Database(MdbError::Panic))?;
-    let app_sk: String = bincode::deserialize(&r_app_sk[..]).unwrap_or_default();
-    log::info!("starting j4i2prs...");
-    let router_override_disabled = std::env::var(IS2FP_ROUTER_OVERRIDE)
-        .unwrap_or("".to_string()).is_empty();
-    // run the main thread forever unless we get a router shutdown signal
+///
+/// The receiver sees each startup transition up to `Ready` or `Failed`,
+///
+/// the sender is dropped once startup is over.
+pub fn start(s: SharedStorage, backend: SharedBackend) -> Result<watch::Receiver<Lifecycle>, ip2p_error::Ip2pError> {
+    let (tx, rx) = watch::channel(Lifecycle::Starting);
+    // run the router on its own thread, it blocks until tunnels can be built
     let _ = thread::spawn(move || {
-        if !router_override_disabled {
-            // don't try to create multiple http proxy tunnels
-            process_tunnels(0, app_sk);
-        } else {
-            let router = rw::Wrapper::create_router().unwrap();
-            std::thread::sleep(std::time::Duration::from_secs(10));
-            let mut run = false;
-            loop {
-                if !run {
-                    log::info!("starting router");
-                    router.invoke_router(rw::METHOD_RUN)
-                        .unwrap_or_else(|_| log::error!("failed to run router"));
-                    run = true;
-                    *IS_ROUTER_RUNNING.lock().unwrap() = true;
-                }
-                let is_running: bool = match IS_ROUTER_RUNNING.lock() {
-                    Ok(m) => *m,
-                    Err(_) => false,
-                };
-                if is_running {
-                    let is_router_on = router.is_running().unwrap_or_default();
-                    if !is_router_on {
-                        log::info!("router is warming up, please wait...");
-                    }
-                    std::thread::sleep(std::time::Duration::from_secs(60));
-                    if is_router_on {
-                        // check router config
-                        process_tunnels(http_proxy_port, app_sk.clone());
-                        break;
-                    }
-                }
-            }
+        // the health supervisor takes over once tunnels are ready
+        if let Err(e) = run(s.as_ref(), backend.as_ref(), &tx) {
+            log::error!("failed to start i2p {} backend: {:?}", backend.name(), e);
+            let reason = format!("{} backend: {:?}", backend.name(), e);
+            report(s.as_ref(), &tx, Lifecycle::Failed(reason))
+                .unwrap_or_else(|_| log::error!("failed to write i2p status."));
         }
     });
-    Ok(())
+    Ok(rx)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        backend::TestBackend,
+        storage::MemoryStorage,
+    };
+
+    #[test]
+    fn lifecycle_test() -> Result<(), ip2p_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        reset_status(&s)?;
+        // tunnels can't be ready before the router is up
+        assert!(set_status(&s, Lifecycle::Ready).is_err());
+        for state in [Lifecycle::RouterWarming, Lifecycle::TunnelsBuilding, Lifecycle::Ready] {
+            set_status(&s, state)?;
+        }
+        set_status(&s, Lifecycle::Degraded)?;
+        set_status(&s, Lifecycle::Ready)?;
+        set_status(&s, Lifecycle::Stopped)?;
+        assert!(set_status(&s, Lifecycle::Failed(String::from("late"))).is_err());
+        let status: Option<I2pStatus> = Store::get(&s, Namespace::Settings, I2P_STATUS)?;
+        let status = HttpProxyStatus::from(status.unwrap_or_default());
+        assert!(!status.open);
+        assert_eq!(Lifecycle::Stopped, status.state);
+        assert_eq!(7, status.transitions.len());
+        assert!(status.transitions.windows(2).all(|t| t[0].at <= t[1].at));
+        Ok(())
+    }
+
+    #[test]
+    fn check_destination_test() -> Result<(), ip2p_error::Ip2pError> {
+        let backend = J4I2prsBackend::default();
+        // Ed25519 destination: keys, key certificate, then the private keys
+        let mut destination: Vec<u8> = (0..384).map(|_| rand::random::<u8>()).collect();
+        destination.extend_from_slice(&[5, 0, 4, 0, 7, 0, 0]);
+        let mut sk = destination.clone();
+        sk.extend((0..288).map(|_| rand::random::<u8>()));
+        let dest = Destination {
+            b32: sam::destination_b32(&sam::encode_i2p_base64(&destination))?,
+            sk: sam::encode_i2p_base64(&sk),
+        };
+        backend.check_destination(&dest)?;
+        let other = Destination { b32: sam::destination_b32(&sam::encode_i2p_base64(&sk[..390]))?, ..dest.clone() };
+        assert!(backend.check_destination(&other).is_err());
+        assert!(backend.check_destination(&Destination { sk: String::from("not a key"), ..dest }).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn start_test() -> Result<(), ip2p_error::Ip2pError> {
+        let s: SharedStorage = Arc::new(MemoryStorage::default());
+        reset_status(s.as_ref())?;
+        let mut rx = start(s.clone(), Arc::new(TestBackend::default()))?;
+        // startup transitions are signaled until the sender is dropped
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| ip2p_error::Ip2pError::Unknown)?;
+        while runtime.block_on(rx.changed()).is_ok() {}
+        assert_eq!(Lifecycle::Ready, *rx.borrow());
+        let status = runtime.block_on(check_connection(s.as_ref()))?;
+        assert_eq!(Lifecycle::Ready, status.state);
+        Ok(())
+    }
 }
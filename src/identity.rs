@@ -0,0 +1,184 @@
+#![deny(missing_docs)]
+
+//! Export and import of the relay identity.
+//!
+//! The destination's secret key and b32 address are written to a file
+//!
+//! sealed with a passphrase, so a relay can keep its address on a new
+//!
+//! machine without copying the whole database.
+
+use crate::{
+    backend::{
+        Destination,
+        I2pBackend,
+    },
+    crypto,
+    db,
+    error as is2fp_error,
+    i2p,
+    rotation,
+    storage::Storage,
+    store::{
+        Namespace,
+        Store,
+    },
+    utils,
+};
+use log::{
+    error,
+    info,
+};
+use std::{
+    fs::OpenOptions,
+    io::{
+        Read,
+        Write,
+    },
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+/// Environment variable for the identity file passphrase
+pub const IS2FP_IDENTITY_PASSPHRASE: &str = "IS2FP_IDENTITY_PASSPHRASE";
+/// Identifies is2fp identity files, also bound to the sealed payload
+const IDENTITY_MAGIC: &[u8] = b"IS2FPID1";
+
+/// Passphrase from `IS2FP_IDENTITY_PASSPHRASE` or the prompt
+pub fn get_passphrase() -> Result<String, is2fp_error::Ip2pError> {
+    crypto::read_passphrase(IS2FP_IDENTITY_PASSPHRASE, "identity")
+}
+
+/// Check with `backend` that the secret key belongs to the b32 address
+pub fn validate(backend: &dyn I2pBackend, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+    backend.check_destination(dest).map_err(|_| {
+        error!("not a {} secret key for {}", backend.name(), dest.b32);
+        is2fp_error::Ip2pError::Identity
+    })
+}
+
+/// Write the relay identity to `path`, sealed with `passphrase`
+pub fn export(s: &dyn Storage, path: &Path, passphrase: &str) -> Result<Destination, is2fp_error::Ip2pError> {
+    let sk: Option<String> = Store::get(s, Namespace::Settings, i2p::APP_I2P_SK)?;
+    let dest = Destination {
+        b32: i2p::get_destination(s)?,
+        sk: sk.unwrap_or_default(),
+    };
+    if dest.sk.is_empty() {
+        error!("this node has no relay identity yet");
+        return Err(is2fp_error::Ip2pError::Identity);
+    }
+    let payload = bincode::serialize(&dest).map_err(is2fp_error::Ip2pError::Encode)?;
+    let mut bytes: Vec<u8> = IDENTITY_MAGIC.to_vec();
+    bytes.extend_from_slice(&crypto::seal_with_passphrase(passphrase, IDENTITY_MAGIC, &payload)?);
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(db::FILE_MODE)
+        .open(path)
+        .and_then(|mut f| f.write_all(&bytes))
+        .map_err(|e| {
+            error!("failed to write identity {}: {}", path.display(), e);
+            is2fp_error::Ip2pError::Identity
+        })?;
+    info!("exported relay identity {} to {}", &dest.b32, path.display());
+    Ok(dest)
+}
+
+/// Read an identity file. Fails on a wrong passphrase or a key that
+///
+/// `backend` can't use for the b32 address.
+pub fn read(backend: &dyn I2pBackend, path: &Path, passphrase: &str) -> Result<Destination, is2fp_error::Ip2pError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    OpenOptions::new()
+        .read(true)
+        .open(path)
+        .and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|e| {
+            error!("failed to read identity {}: {}", path.display(), e);
+            is2fp_error::Ip2pError::Identity
+        })?;
+    let not_identity = || {
+        error!("{} is not an is2fp identity", path.display());
+        is2fp_error::Ip2pError::Identity
+    };
+    let sealed = bytes.strip_prefix(IDENTITY_MAGIC).ok_or_else(not_identity)?;
+    let payload = match crypto::open_with_passphrase(passphrase, IDENTITY_MAGIC, sealed) {
+        Ok(payload) => payload,
+        Err(is2fp_error::Ip2pError::Passphrase) => {
+            error!("wrong identity passphrase");
+            return Err(is2fp_error::Ip2pError::Passphrase);
+        }
+        Err(_) => return Err(not_identity()),
+    };
+    let dest: Destination = bincode::deserialize(&payload).map_err(is2fp_error::Ip2pError::Decode)?;
+    validate(backend, &dest)?;
+    Ok(dest)
+}
+
+/// Store the identity from `path` as the relay destination of a node
+///
+/// that doesn't have one yet
+pub fn import(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+    path: &Path,
+    passphrase: &str,
+) -> Result<Destination, is2fp_error::Ip2pError> {
+    let dest = read(backend, path, passphrase)?;
+    let existing: Option<String> = Store::get(s, Namespace::Settings, i2p::APP_I2P_SK)?;
+    if existing.is_some_and(|sk| !sk.is_empty()) {
+        error!("refusing to import over an existing relay identity");
+        return Err(is2fp_error::Ip2pError::Identity);
+    }
+    let entries = vec![
+        Store::entry(Namespace::Settings, i2p::APP_B32_DEST, &dest.b32)?,
+        Store::entry(Namespace::Settings, i2p::APP_I2P_SK, &dest.sk)?,
+        Store::entry(Namespace::Settings, rotation::APP_DEST_CREATED, &utils::get_unix_time()?)?,
+    ];
+    Store::put_all(s, &entries)?;
+    info!("imported relay identity {}", &dest.b32);
+    Ok(dest)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        backend::TestBackend,
+        storage::MemoryStorage,
+    };
+
+    #[test]
+    fn export_import_test() -> Result<(), is2fp_error::Ip2pError> {
+        let src = MemoryStorage::default();
+        let dst = MemoryStorage::default();
+        let backend = TestBackend::default();
+        let dest = backend.create_destination()?;
+        Store::put(&src, Namespace::Settings, i2p::APP_B32_DEST, &dest.b32)?;
+        Store::put(&src, Namespace::Settings, i2p::APP_I2P_SK, &dest.sk)?;
+        let path = std::env::temp_dir().join(format!("is2fp-identity-{}", rand::random::<u64>()));
+        export(&src, &path, "correct horse")?;
+        let wrong = import(&dst, &backend, &path, "battery staple");
+        assert!(matches!(wrong, Err(is2fp_error::Ip2pError::Passphrase)));
+        assert_eq!(dest, import(&dst, &backend, &path, "correct horse")?);
+        assert_eq!(dest.b32, i2p::get_destination(&dst)?);
+        // the node now has an identity
+        assert!(import(&dst, &backend, &path, "correct horse").is_err());
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[test]
+    fn validate_test() -> Result<(), is2fp_error::Ip2pError> {
+        let backend = TestBackend::default();
+        let dest = backend.create_destination()?;
+        validate(&backend, &dest)?;
+        let other = backend.create_destination()?;
+        assert!(validate(&backend, &Destination { b32: other.b32, sk: dest.sk }).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+#![deny(missing_docs)]
+
+//! Per-message storage for the inbox.
+//!
+//! Each message is stored under `inbox-{created}-{mid}` so writes don't
+//!
+//! depend on the size of the inbox and keys sort by creation time.
+
+use crate::{
+    error as is2fp_error,
+    storage::Storage,
+    store::{
+        Namespace,
+        Store,
+    },
+    utils::Message,
+};
+use sha2::{
+    Digest,
+    Sha512,
+};
+
+/// Inbox id of a message. Messages without a `mid` use the hash of their data.
+pub fn id(msg: &Message) -> String {
+    let mid = if msg.mid.is_empty() {
+        let mut hasher = Sha512::new();
+        hasher.update(msg.data.as_bytes());
+        hex::encode(&hasher.finalize()[..])
+    } else {
+        String::from(&msg.mid)
+    };
+    // zero padded so lexical order matches creation order
+    format!("{:020}-{}", msg.created, mid)
+}
+
+/// Creation time encoded in an inbox id, `None` if it isn't one
+pub fn created(id: &str) -> Option<u64> {
+    id.split_once('-').and_then(|(created, _)| created.parse().ok())
+}
+
+/// Ids of every message, oldest first. Messages aren't read.
+pub fn ids(s: &dyn Storage) -> Result<Vec<String>, is2fp_error::Ip2pError> {
+    Store::keys(s, Namespace::Inbox)
+}
+
+/// Save a message to the inbox, returning its id
+pub fn save(s: &dyn Storage, msg: &Message) -> Result<String, is2fp_error::Ip2pError> {
+    let id = id(msg);
+    Store::put(s, Namespace::Inbox, &id, msg)?;
+    Ok(id)
+}
+
+/// Number of messages in the inbox, counted by key
+pub fn count(s: &dyn Storage) -> Result<usize, is2fp_error::Ip2pError> {
+    Ok(ids(s)?.len())
+}
+
+/// Up to `limit` messages starting at `offset`, oldest first. Only the
+///
+/// messages in the window are read.
+pub fn page(
+    s: &dyn Storage,
+    offset: usize,
+    limit: usize,
+) -> Result<Vec<(String, Message)>, is2fp_error::Ip2pError> {
+    let mut messages: Vec<(String, Message)> = Vec::new();
+    for id in ids(s)?.into_iter().skip(offset).take(limit) {
+        if let Some(msg) = Store::get(s, Namespace::Inbox, &id)? {
+            messages.push((id, msg));
+        }
+    }
+    Ok(messages)
+}
+
+/// Delete a message by id
+pub fn delete(s: &dyn Storage, id: &str) -> Result<(), is2fp_error::Ip2pError> {
+    Store::delete(s, Namespace::Inbox, id)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn inbox_test() -> Result<(), is2fp_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        for (created, mid) in [(30, "c"), (10, "a"), (20, "b")] {
+            let msg = Message { created, mid: String::from(mid), ..Default::default() };
+            save(&s, &msg)?;
+        }
+        assert_eq!(3, count(&s)?);
+        assert_eq!(Some(10), created(&ids(&s)?[0]));
+        assert_eq!(None, created("not-an-id"));
+        let first = page(&s, 0, 2)?;
+        assert_eq!(vec!["a", "b"], first.iter().map(|(_, m)| m.mid.as_str()).collect::<Vec<_>>());
+        let rest = page(&s, 2, 2)?;
+        assert_eq!(1, rest.len());
+        delete(&s, &first[0].0)?;
+        assert_eq!(2, count(&s)?);
+        // messages outside the window aren't decoded
+        s.write(&Namespace::Inbox.key(&format!("{:020}-z", 40)), &[0xff])
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        assert_eq!(3, count(&s)?);
+        assert_eq!(2, page(&s, 0, 2)?.len());
+        assert!(page(&s, 2, 1).is_err());
+        Ok(())
+    }
+}
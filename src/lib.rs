@@ -1,4 +1,23 @@
+pub mod backend;
+pub mod backup;
+pub mod crypto;
 pub mod db;
 pub mod error;
+pub mod health;
 pub mod i2p;
+pub mod identity;
+pub mod inbox;
+pub mod loopback;
+pub mod migration;
+pub mod proxy;
+pub mod reseed;
+pub mod retention;
+pub mod rotation;
+pub mod router_config;
+pub mod sam;
+pub mod shutdown;
+pub mod stem;
+pub mod storage;
+pub mod store;
+pub mod tunnel_config;
 pub mod utils;
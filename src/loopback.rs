@@ -0,0 +1,209 @@
+#![deny(missing_docs)]
+
+//! Loopback I2P emulator for tests without a router.
+//!
+//! Destinations get fake b32 names that are registered in a directory
+//!
+//! shared by every node on the machine. Requests to a b32 name go
+//!
+//! straight to the local port published for it.
+
+use crate::{
+    backend::{
+        Destination,
+        I2pBackend,
+    },
+    error as is2fp_error,
+    sam,
+};
+use futures::future::BoxFuture;
+use log::{
+    error,
+    info,
+};
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{
+            AtomicBool,
+            Ordering,
+        },
+        Mutex,
+    },
+};
+
+/// Environment variable for the directory mapping b32 names to ports
+pub const IS2FP_LOOPBACK_DIR: &str = "IS2FP_LOOPBACK_DIR";
+/// Default registry directory under the system temp dir
+const LOOPBACK_DIR: &str = "is2fp-loopback";
+/// Bytes of a fake secret key, the b32 name is derived from its hash
+const SECRET_KEY_LENGTH: usize = 32;
+
+/// Emulated router routing b32 names to local ports
+pub struct LoopbackBackend {
+    /// Registry shared by the nodes on this machine
+    dir: PathBuf,
+    running: AtomicBool,
+    /// Names this backend published
+    published: Mutex<Vec<String>>,
+}
+
+impl LoopbackBackend {
+    /// Backend using the registry in `dir`
+    pub fn new(dir: PathBuf) -> Self {
+        LoopbackBackend {
+            dir,
+            running: AtomicBool::new(false),
+            published: Mutex::new(Vec::new()),
+        }
+    }
+    /// Backend using the registry in `IS2FP_LOOPBACK_DIR`
+    pub fn from_env() -> Self {
+        match std::env::var(IS2FP_LOOPBACK_DIR) {
+            Ok(dir) if !dir.is_empty() => LoopbackBackend::new(PathBuf::from(dir)),
+            _ => LoopbackBackend::new(std::env::temp_dir().join(LOOPBACK_DIR)),
+        }
+    }
+    /// Registry file of `b32`. Names from peers are checked before they
+    ///
+    /// become a path.
+    fn entry(&self, b32: &str) -> Result<PathBuf, is2fp_error::Ip2pError> {
+        let name = b32.strip_suffix(".b32.i2p").unwrap_or_default();
+        if name.len() != 52 || !name.bytes().all(|c| matches!(c, b'a'..=b'z' | b'2'..=b'7')) {
+            error!("not a b32 address: {}", b32);
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+        Ok(self.dir.join(b32))
+    }
+    /// Local port published for `b32`
+    pub fn lookup(&self, b32: &str) -> Result<u16, is2fp_error::Ip2pError> {
+        let port = std::fs::read_to_string(self.entry(b32)?).map_err(|_| {
+            error!("unknown loopback destination {}", b32);
+            is2fp_error::Ip2pError::Relay
+        })?;
+        port.trim().parse::<u16>().map_err(|_| is2fp_error::Ip2pError::Relay)
+    }
+}
+
+impl I2pBackend for LoopbackBackend {
+    fn name(&self) -> &'static str {
+        "loopback"
+    }
+    fn start_router(&self) -> Result<(), is2fp_error::Ip2pError> {
+        std::fs::create_dir_all(&self.dir).map_err(|e| {
+            error!("failed to create {}: {}", self.dir.display(), e);
+            is2fp_error::Ip2pError::I2P
+        })?;
+        info!("emulating i2p with the registry in {}", self.dir.display());
+        self.running.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+    fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+    fn create_destination(&self) -> Result<Destination, is2fp_error::Ip2pError> {
+        let key: [u8; SECRET_KEY_LENGTH] = rand::random();
+        Ok(Destination { b32: sam::b32_of(&key), sk: hex::encode(key) })
+    }
+    fn check_destination(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        let key = hex::decode(&dest.sk).map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        if key.len() != SECRET_KEY_LENGTH || sam::b32_of(&key) != dest.b32 {
+            error!("secret key doesn't belong to {}", dest.b32);
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+        Ok(())
+    }
+    fn start_server_tunnel(&self, dest: &Destination, port: u16) -> Result<(), is2fp_error::Ip2pError> {
+        std::fs::write(self.entry(&dest.b32)?, port.to_string()).map_err(|e| {
+            error!("failed to register {}: {}", dest.b32, e);
+            is2fp_error::Ip2pError::I2P
+        })?;
+        self.published
+            .lock()
+            .map_err(|_| is2fp_error::Ip2pError::I2P)?
+            .push(dest.b32.clone());
+        info!("forwarding {} to port {}", dest.b32, port);
+        Ok(())
+    }
+    fn stop_server_tunnel(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        let _ = std::fs::remove_file(self.entry(&dest.b32)?);
+        self.published
+            .lock()
+            .map_err(|_| is2fp_error::Ip2pError::I2P)?
+            .retain(|b32| b32 != &dest.b32);
+        Ok(())
+    }
+    fn check_proxy(&self) -> bool {
+        self.is_running()
+    }
+    fn restart_proxy(&self) -> Result<(), is2fp_error::Ip2pError> {
+        Ok(())
+    }
+    fn stop(&self) -> Result<(), is2fp_error::Ip2pError> {
+        let mut published = self.published.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        for b32 in published.drain(..) {
+            let _ = std::fs::remove_file(self.dir.join(b32));
+        }
+        self.running.store(false, Ordering::SeqCst);
+        Ok(())
+    }
+    fn open_client(&self) -> Result<String, is2fp_error::Ip2pError> {
+        Ok(format!("loopback-{}", rand::random::<u32>()))
+    }
+    fn close_client(&self, _: &str) -> Result<(), is2fp_error::Ip2pError> {
+        Ok(())
+    }
+    fn post_json<'a>(
+        &'a self,
+        _: Option<&'a str>,
+        b32: &'a str,
+        path: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, is2fp_error::Ip2pError>> {
+        Box::pin(async move {
+            let port = self.lookup(b32)?;
+            let client = reqwest::Client::builder()
+                .no_proxy()
+                .build()
+                .map_err(|_| is2fp_error::Ip2pError::Relay)?;
+            let response = client
+                .post(format!("http://127.0.0.1:{}{}", port, path))
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body)
+                .send()
+                .await
+                .map_err(|e| {
+                    error!("failed to relay due to: {:?}", e);
+                    is2fp_error::Ip2pError::Relay
+                })?;
+            let bytes = response.bytes().await.map_err(|_| is2fp_error::Ip2pError::Relay)?;
+            Ok(bytes.to_vec())
+        })
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn registry_test() -> Result<(), is2fp_error::Ip2pError> {
+        let dir = std::env::temp_dir().join(format!("is2fp-loopback-{}", rand::random::<u64>()));
+        let backend = LoopbackBackend::new(dir.clone());
+        backend.start_router()?;
+        let dest = backend.create_destination()?;
+        backend.check_destination(&dest)?;
+        let other = backend.create_destination()?;
+        assert!(backend.check_destination(&Destination { b32: other.b32, ..dest.clone() }).is_err());
+        backend.start_server_tunnel(&dest, 5556)?;
+        assert_eq!(5556, LoopbackBackend::new(dir.clone()).lookup(&dest.b32)?);
+        backend.stop()?;
+        assert!(backend.lookup(&dest.b32).is_err());
+        assert!(backend.lookup("../../etc/passwd.b32.i2p").is_err());
+        let _ = std::fs::remove_dir_all(&dir);
+        Ok(())
+    }
+}
@@ -3,14 +3,34 @@ extern crate rocket;
 
 use rocket::{
     catch,
+    fairing::AdHoc,
     get,
     http::Status,
     post,
     response::status::Custom,
     serde::json::Json,
+    State,
 };
+use std::sync::Arc;
 
-use is2fp::{i2p, error as ip2p_error, utils};
+use is2fp::{
+    backend,
+    backup,
+    db,
+    health,
+    i2p,
+    identity,
+    error as ip2p_error,
+    inbox,
+    migration,
+    shutdown::SharedShutdown,
+    storage::SharedStorage,
+    store::{
+        Namespace,
+        Store,
+    },
+    utils,
+};
 
 // Catchers
 //----------------------------------------------------------------
@@ -36,44 +56,126 @@ pub fn internal_error() -> Custom<Json<ip2p_error::ErrorResponse>> {
 // End Catchers
 //----------------------------------------------------------------
 
-/// If i2p not in the state of rejecting tunnels this will return `open: true`
+/// Lifecycle state of the router and tunnels with transition timestamps.
+///
+/// `open: true` while the relay server is reachable over i2p.
 ///
 /// This also functions as a health check
 #[get("/status")]
-pub async fn get_i2p_status() -> Custom<Json<i2p::HttpProxyStatus>> {
-    let status = i2p::check_connection().await;
-    if status.unwrap_or(i2p::ProxyStatus::Opening) == i2p::ProxyStatus::Open {
-        Custom(Status::Ok, Json(i2p::HttpProxyStatus { open: true }))
-    } else {
-        Custom(Status::Ok, Json(i2p::HttpProxyStatus { open: false }))
-    }
+pub async fn get_i2p_status(storage: &State<SharedStorage>) -> Custom<Json<i2p::HttpProxyStatus>> {
+    let status = i2p::check_connection(storage.inner().as_ref()).await;
+    Custom(Status::Ok, Json(i2p::HttpProxyStatus::from(status.unwrap_or_default())))
+}
+
+/// Echo a health probe sent through our own tunnels
+#[post("/probe", data = "<probe>")]
+pub async fn probe(probe: Json<health::Probe>) -> Custom<Json<health::Probe>> {
+    Custom(Status::Ok, probe)
 }
 
 /// Recieve messages here
 #[post("/", data = "<message>")]
-pub async fn message(message: Json<utils::Message>) -> Custom<Json<utils::Message>> {
-    utils::inject_fluff(message)
+pub async fn message(
+    storage: &State<SharedStorage>,
+    shutdown: &State<SharedShutdown>,
+    message: Json<utils::Message>,
+) -> Custom<Json<utils::Message>> {
+    if shutdown.is_triggered() {
+        return Custom(Status::ServiceUnavailable, Json(Default::default()));
+    }
+    utils::inject_fluff(storage.inner().as_ref(), message)
         .unwrap_or_else(|_| log::error!("failed to inject message for fluff propagation"));
     Custom(Status::Ok, Json(Default::default()))
 }
 
-// Launch the i2p relay server
-#[launch]
-async fn rocket() -> _ {
+/// Run a maintenance command instead of the relay server.
+///
+/// Commands that only read open the database read-only, so they can run
+///
+/// next to a live node. Returns `false` if no command was given.
+fn run_command(args: &[String]) -> Result<bool, ip2p_error::Ip2pError> {
+    let path = || {
+        args.get(1).map(std::path::Path::new).ok_or_else(|| {
+            log::error!("usage: is2fp {} <FILE>", args[0]);
+            ip2p_error::Ip2pError::Unknown
+        })
+    };
+    let readonly = || db::open_default_readonly().map_err(ip2p_error::Ip2pError::Database);
+    match args.first().map(String::as_str) {
+        Some("export") => {
+            let l = readonly()?;
+            backup::export(&l, path()?, &backup::get_passphrase()?)?;
+        }
+        Some("import") => {
+            let l = db::open_default().map_err(ip2p_error::Ip2pError::Database)?;
+            backup::import(&l, path()?, &backup::get_passphrase()?)?;
+        }
+        Some("export-identity") => {
+            let l = readonly()?;
+            identity::export(&l, path()?, &identity::get_passphrase()?)?;
+        }
+        Some("import-identity") => {
+            let l = db::open_default().map_err(ip2p_error::Ip2pError::Database)?;
+            let backend = backend::from_env()?;
+            let dest = identity::import(&l, backend.as_ref(), path()?, &identity::get_passphrase()?)?;
+            println!("relay server address - {}", dest.b32);
+        }
+        Some("inspect") => {
+            let l = readonly()?;
+            let version = migration::get_version(&l).map_err(ip2p_error::Ip2pError::Database)?;
+            let relays: Vec<(String, utils::Relay)> = Store::scan(&l, Namespace::Relay)?;
+            println!("schema version: {}", version);
+            println!("relays: {}", relays.len());
+            println!("inbox messages: {}", inbox::count(&l)?);
+        }
+        Some(command) => {
+            log::error!("unknown command: {}", command);
+            return Err(ip2p_error::Ip2pError::Unknown);
+        }
+        None => return Ok(false),
+    };
+    Ok(true)
+}
+
+// Parse flags and run commands before the runtime starts its threads
+fn main() -> Result<(), ip2p_error::Ip2pError> {
     env_logger::init();
+    utils::apply_args();
+    if run_command(&utils::get_command_args())? {
+        return Ok(());
+    }
+    rocket::execute(launch())
+}
+
+// Launch the i2p relay server
+async fn launch() -> Result<(), ip2p_error::Ip2pError> {
+    let db = db::open_default().map_err(ip2p_error::Ip2pError::Database)?;
+    let storage: SharedStorage = Arc::new(db);
+    let backend = backend::from_env()?;
+    let shutdown: SharedShutdown = Arc::new(Default::default());
     let config = rocket::Config {
         ident: rocket::config::Ident::none(),
         ip_header: None,
         port: utils::get_app_port(),
         ..rocket::Config::debug_default()
     };
-    utils::start_up().await.expect("i2p start failure");
-    rocket::custom(&config)
+    utils::start_up(storage.clone(), backend.clone(), shutdown.clone()).await?;
+    let _ = rocket::custom(&config)
         .register(
             "/",
             catchers![internal_error, not_found],
         )
+        .attach(AdHoc::on_shutdown("is2fp shutdown", {
+            let (storage, shutdown) = (storage.clone(), shutdown.clone());
+            move |_| Box::pin(async move { shutdown.run(storage.as_ref(), backend).await })
+        }))
+        .manage(storage)
+        .manage(shutdown)
         .mount("/message", routes![message])
-        .mount("/i2p", routes![get_i2p_status])
+        .mount("/i2p", routes![get_i2p_status, probe])
+        .launch()
+        .await
+        .map_err(ip2p_error::Ip2pError::RocketError)?;
+    Ok(())
 }
 
@@ -0,0 +1,307 @@
+#![deny(missing_docs)]
+
+//! Schema versioning and migrations for the LMDB store.
+//!
+//! `DatabaseEnvironment::open` runs every migration newer than the
+//!
+//! stored schema version. Databases without a version key are version 0.
+
+use crate::{
+    db,
+    i2p,
+    store::Namespace,
+    utils,
+};
+use bincode::Options;
+use kn0sys_lmdb_rs::MdbError;
+use log::{
+    error,
+    info,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use sha2::{
+    Digest,
+    Sha512,
+};
+
+/// Key holding the schema version of the database
+pub const SCHEMA_VERSION_KEY: &str = "schema-version";
+/// Schema version written by this build
+pub const CURRENT_SCHEMA_VERSION: u32 = 4;
+
+/// `MessageType` as stored up to schema version 2
+#[derive(Debug, Deserialize, Serialize)]
+enum MessageTypeV1 {
+    B32Exchange,
+    Stem,
+    Fluff,
+}
+
+/// `Message` as stored up to schema version 2. Migrations decode the
+///
+/// layout of the version they upgrade from, never the live types.
+#[derive(Debug, Deserialize, Serialize)]
+struct MessageV1 {
+    mid: String,
+    data: String,
+    created: u64,
+    from: String,
+    to: String,
+    m_type: MessageTypeV1,
+    fluff_probability: f64,
+    pow_problem: String,
+    pow_solution: String,
+}
+
+/// `Relay` as written by schema version 3
+#[derive(Debug, Deserialize, Serialize)]
+struct RelayV3 {
+    b32: String,
+    seen: u64,
+}
+
+/// The i2p proxy status stored under `I2P_STATUS` up to schema version 3
+#[derive(Debug, Deserialize, Serialize)]
+enum ProxyStatusV3 {
+    Opening,
+    Open,
+}
+
+/// A single upgrade step from `from` to `from + 1`
+struct Migration {
+    from: u32,
+    description: &'static str,
+    run: fn(&db::DatabaseEnvironment) -> Result<(), MdbError>,
+}
+
+/// Migrations in the order they are applied
+const MIGRATIONS: [Migration; 4] = [
+    Migration {
+        from: 0,
+        description: "add chunk manifests to unversioned values",
+        run: add_chunk_manifests,
+    },
+    Migration {
+        from: 1,
+        description: "store inbox messages under their own keys",
+        run: split_inbox,
+    },
+    Migration {
+        from: 2,
+        description: "record when relays were last seen",
+        run: add_relay_timestamps,
+    },
+    Migration {
+        from: 3,
+        description: "drop the legacy proxy status",
+        run: drop_proxy_status,
+    },
+];
+
+/// Read the schema version. Missing versions are treated as 0.
+pub fn get_version(l: &db::DatabaseEnvironment) -> Result<u32, MdbError> {
+    let k = SCHEMA_VERSION_KEY.as_bytes().to_vec();
+    let r = l.read(&k)?;
+    if r.is_empty() {
+        return Ok(0);
+    }
+    bincode::deserialize(&r[..]).map_err(|e| {
+        error!("failed to decode schema version: {:?}", e);
+        MdbError::Corrupted
+    })
+}
+
+/// Record the schema version
+pub(crate) fn set_version(l: &db::DatabaseEnvironment, version: u32) -> Result<(), MdbError> {
+    let v = bincode::serialize(&version).map_err(|_| MdbError::Corrupted)?;
+    db::write_chunks(l, SCHEMA_VERSION_KEY.as_bytes(), &v)
+}
+
+/// Upgrade the database to `CURRENT_SCHEMA_VERSION`.
+///
+/// The version is recorded after each step so an interrupted upgrade
+///
+/// resumes from the last completed migration.
+pub fn migrate(l: &db::DatabaseEnvironment) -> Result<(), MdbError> {
+    let mut version = get_version(l)?;
+    if version > CURRENT_SCHEMA_VERSION {
+        error!(
+            "database schema version {} is newer than supported version {}",
+            version, CURRENT_SCHEMA_VERSION
+        );
+        return Err(MdbError::StateError(format!("unsupported schema version {}", version)));
+    }
+    for m in MIGRATIONS.iter().filter(|m| m.from >= version) {
+        info!("migrating schema from {} to {}: {}", m.from, m.from + 1, m.description);
+        (m.run)(l)?;
+        version = m.from + 1;
+        set_version(l, version)?;
+    }
+    info!("schema version {}", version);
+    Ok(())
+}
+
+/// Version 0 values were written chunk by chunk without a manifest.
+///
+/// `db::upgrade_manifests` adds one when the environment opens, rewrite
+///
+/// the fixed keys so they are stored in the current layout.
+fn add_chunk_manifests(l: &db::DatabaseEnvironment) -> Result<(), MdbError> {
+    let keys: Vec<Vec<u8>> = vec![
+        Namespace::Inbox.key(""),
+        Namespace::Fluff.key(""),
+        Namespace::Settings.key(i2p::I2P_STATUS),
+        Namespace::Settings.key(i2p::APP_B32_DEST),
+        Namespace::Settings.key(i2p::APP_I2P_SK),
+    ];
+    for k in keys {
+        let v = l.read(&k)?;
+        if !v.is_empty() {
+            db::write_chunks(l, &k, &v)?;
+        }
+    }
+    Ok(())
+}
+
+/// Version 1 stored the inbox as one `Vec<Message>` under "inbox".
+///
+/// Move each message to `inbox-{created}-{mid}`.
+fn split_inbox(l: &db::DatabaseEnvironment) -> Result<(), MdbError> {
+    let k = Namespace::Inbox.key("");
+    let r = l.read(&k)?;
+    if r.is_empty() {
+        return Ok(());
+    }
+    let messages: Vec<MessageV1> = bincode::deserialize(&r[..]).map_err(|e| {
+        error!("failed to decode inbox: {:?}", e);
+        MdbError::Corrupted
+    })?;
+    info!("moving {} inbox messages", messages.len());
+    for msg in messages {
+        // ids of version 2: creation time, then the mid or the data hash
+        let mid = if msg.mid.is_empty() {
+            hex::encode(&Sha512::digest(msg.data.as_bytes())[..])
+        } else {
+            msg.mid.clone()
+        };
+        let id = format!("{:020}-{}", msg.created, mid);
+        let v = bincode::serialize(&msg).map_err(|_| MdbError::Corrupted)?;
+        db::write_chunks(l, &Namespace::Inbox.key(&id), &v)?;
+    }
+    db::DatabaseEnvironment::delete(&l.env, &l.handle, &k)
+}
+
+/// Version 2 stored relays as their b32 address only. Relays are
+///
+/// treated as seen at the time of the upgrade.
+fn add_relay_timestamps(l: &db::DatabaseEnvironment) -> Result<(), MdbError> {
+    let seen = utils::get_unix_time().map_err(|_| MdbError::Corrupted)?;
+    let prefix = Namespace::Relay.scan_prefix();
+    for (k, v) in l.scan_prefix(&prefix)? {
+        if v.is_empty() {
+            continue;
+        }
+        let b32: String = bincode::deserialize(&v[..]).map_err(|e| {
+            error!("failed to decode relay {:?}: {:?}", k, e);
+            MdbError::Corrupted
+        })?;
+        let relay = RelayV3 { b32, seen };
+        let v = bincode::serialize(&relay).map_err(|_| MdbError::Corrupted)?;
+        db::write_chunks(l, &k, &v)?;
+    }
+    Ok(())
+}
+
+/// Version 3 and earlier could hold the `ProxyStatus` of older builds
+///
+/// under `I2P_STATUS`. It can't be decoded as a lifecycle status and is
+///
+/// dropped, the node writes a fresh status on startup.
+fn drop_proxy_status(l: &db::DatabaseEnvironment) -> Result<(), MdbError> {
+    let k = Namespace::Settings.key(i2p::I2P_STATUS);
+    let r = l.read(&k)?;
+    // the whole value must be a proxy status, lifecycle statuses are longer
+    let legacy = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .deserialize::<ProxyStatusV3>(&r[..]);
+    if legacy.is_err() {
+        return Ok(());
+    }
+    info!("dropping legacy i2p status");
+    db::DatabaseEnvironment::delete(&l.env, &l.handle, &k)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use kn0sys_lmdb_rs::{
+        DbFlags,
+        EnvBuilder,
+    };
+
+    /// Raw keys and values of a version 0 database
+    const SCHEMA_V0: &str = include_str!("../tests/fixtures/schema_v0.hex");
+
+    /// Write the fixture to a new LMDB environment at `path`, bypassing
+    ///
+    /// `DatabaseEnvironment` so nothing but the fixture is stored
+    fn load_fixture(path: &std::path::Path) -> Result<(), MdbError> {
+        std::fs::create_dir_all(path).map_err(|_| MdbError::Panic)?;
+        let env = EnvBuilder::new().open(path, db::FILE_MODE)?;
+        let handle = env.get_default_db(DbFlags::empty())?;
+        let txn = env.new_transaction()?;
+        {
+            let db = txn.bind(&handle);
+            for line in SCHEMA_V0.lines().filter(|l| !l.starts_with('#')) {
+                let (k, v) = line.split_once(' ').ok_or(MdbError::Corrupted)?;
+                let k = hex::decode(k).map_err(|_| MdbError::Corrupted)?;
+                let v = hex::decode(v).map_err(|_| MdbError::Corrupted)?;
+                db.set(&k, &v)?;
+            }
+        }
+        txn.commit()
+    }
+
+    #[test]
+    fn migrate_v0_test() -> Result<(), MdbError> {
+        let path = std::env::temp_dir().join(format!("is2fp-test-{}", rand::random::<u64>()));
+        load_fixture(&path)?;
+        // opening the environment adds manifests, then migrates
+        let result = db::DatabaseEnvironment::open_at(&path).and_then(|l| {
+            assert_eq!(CURRENT_SCHEMA_VERSION, get_version(&l)?);
+            assert!(l.read(&Namespace::Inbox.key(""))?.is_empty());
+            let inbox_key = Namespace::Inbox.key(&format!("{:020}-mid", 1700000000));
+            let actual: utils::Message =
+                bincode::deserialize(&l.read(&inbox_key)?[..]).map_err(|_| MdbError::Corrupted)?;
+            assert_eq!("data", actual.data);
+            assert_eq!("peer", actual.to);
+            assert_eq!(utils::MessageType::Stem, actual.m_type);
+            // the legacy status is gone
+            let status_key = Namespace::Settings.key(i2p::I2P_STATUS);
+            assert!(l.read(&status_key)?.is_empty());
+            let relay = l.read(&Namespace::Relay.key("test-peer"))?;
+            let actual: utils::Relay =
+                bincode::deserialize(&relay[..]).map_err(|_| MdbError::Corrupted)?;
+            assert_eq!("test.b32.i2p", actual.b32);
+            assert!(actual.seen > 0);
+            let b32: String = bincode::deserialize(&l.read(i2p::APP_B32_DEST.as_bytes())?[..])
+                .map_err(|_| MdbError::Corrupted)?;
+            assert_eq!("fixture.b32.i2p", b32);
+            // lifecycle statuses are kept
+            let current =
+                bincode::serialize(&i2p::I2pStatus::default()).map_err(|_| MdbError::Corrupted)?;
+            db::write_chunks(&l, &status_key, &current)?;
+            drop_proxy_status(&l)?;
+            assert_eq!(current, l.read(&status_key)?);
+            Ok(())
+        });
+        let _ = std::fs::remove_dir_all(&path);
+        result
+    }
+}
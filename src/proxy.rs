@@ -0,0 +1,147 @@
+#![deny(missing_docs)]
+
+//! Outbound proxy for requests to other relays.
+//!
+//! `I2P_PROXY_HOST` selects an i2p http proxy, e.g. `http://127.0.0.1:4242`,
+//!
+//! or an i2p SOCKS tunnel, e.g. `socks5://127.0.0.1:4447`.
+
+use crate::{
+    error as is2fp_error,
+    i2p,
+};
+use log::error;
+use std::fmt;
+
+/// Default port of an i2p SOCKS tunnel
+pub const DEFAULT_SOCKS_PROXY_PORT: u16 = 4447;
+
+/// Protocol spoken by the proxy
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ProxyScheme {
+    /// i2p http proxy
+    #[default]
+    Http,
+    /// i2p SOCKS tunnel, b32 names are resolved by the proxy
+    Socks5,
+}
+
+/// Parsed proxy address
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProxyUrl {
+    /// Protocol spoken by the proxy
+    pub scheme: ProxyScheme,
+    /// Host name or ip address
+    pub host: String,
+    /// Port of the proxy
+    pub port: u16,
+}
+
+impl Default for ProxyUrl {
+    fn default() -> Self {
+        ProxyUrl {
+            scheme: ProxyScheme::Http,
+            host: String::from("127.0.0.1"),
+            port: i2p::DEFAULT_HTTP_PROXY_PORT,
+        }
+    }
+}
+
+impl ProxyUrl {
+    /// Parse `[scheme://]host[:port][/]`. The scheme defaults to `http`
+    ///
+    /// and the port to the default of the scheme.
+    pub fn parse(v: &str) -> Result<Self, is2fp_error::Ip2pError> {
+        let invalid = |reason: &str| {
+            error!("invalid {} {:?}: {}", i2p::I2P_PROXY_HOST, v, reason);
+            is2fp_error::Ip2pError::I2P
+        };
+        let v = v.trim();
+        let (scheme, rest) = match v.split_once("://") {
+            Some((scheme, rest)) => (scheme.to_ascii_lowercase(), rest),
+            None => (String::from("http"), v),
+        };
+        let scheme = match scheme.as_str() {
+            "http" => ProxyScheme::Http,
+            "socks5" | "socks5h" => ProxyScheme::Socks5,
+            _ => return Err(invalid("scheme must be http, socks5 or socks5h")),
+        };
+        let authority = rest.strip_suffix('/').unwrap_or(rest);
+        if authority.contains(['/', '@', '?', '#']) {
+            return Err(invalid("expected only a host and port"));
+        }
+        // the port follows the last colon, unless it is part of an ipv6 address
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.ends_with(']') => {
+                let port = port.parse::<u16>().ok().filter(|p| *p != 0);
+                (host, Some(port.ok_or_else(|| invalid("port must be between 1 and 65535"))?))
+            }
+            _ => (authority, None),
+        };
+        if host.is_empty() {
+            return Err(invalid("missing host"));
+        }
+        let default_port = match scheme {
+            ProxyScheme::Http => i2p::DEFAULT_HTTP_PROXY_PORT,
+            ProxyScheme::Socks5 => DEFAULT_SOCKS_PROXY_PORT,
+        };
+        Ok(ProxyUrl {
+            scheme,
+            host: String::from(host),
+            port: port.unwrap_or(default_port),
+        })
+    }
+    /// Proxy from `I2P_PROXY_HOST`, the default http proxy if unset
+    pub fn from_env() -> Result<Self, is2fp_error::Ip2pError> {
+        match std::env::var(i2p::I2P_PROXY_HOST) {
+            Ok(v) if !v.trim().is_empty() => ProxyUrl::parse(&v),
+            _ => Ok(Default::default()),
+        }
+    }
+    /// Proxy for reqwest. SOCKS requests leave name resolution to the
+    ///
+    /// proxy, b32 names only resolve inside i2p.
+    pub fn to_reqwest(&self) -> Result<reqwest::Proxy, is2fp_error::Ip2pError> {
+        let proxy = match self.scheme {
+            ProxyScheme::Http => reqwest::Proxy::http(self.to_string()),
+            ProxyScheme::Socks5 => reqwest::Proxy::all(self.to_string()),
+        };
+        proxy.map_err(|e| {
+            error!("failed to use proxy {}: {:?}", self, e);
+            is2fp_error::Ip2pError::Relay
+        })
+    }
+}
+
+impl fmt::Display for ProxyUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.scheme {
+            ProxyScheme::Http => "http",
+            ProxyScheme::Socks5 => "socks5h",
+        };
+        write!(f, "{}://{}:{}", scheme, self.host, self.port)
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_test() -> Result<(), is2fp_error::Ip2pError> {
+        assert_eq!(ProxyUrl::default(), ProxyUrl::parse("http://127.0.0.1:4242")?);
+        assert_eq!(ProxyUrl::default(), ProxyUrl::parse("127.0.0.1")?);
+        let proxy = ProxyUrl::parse("localhost:4444/")?;
+        assert_eq!((ProxyScheme::Http, "localhost", 4444), (proxy.scheme, proxy.host.as_str(), proxy.port));
+        let proxy = ProxyUrl::parse("SOCKS5://[::1]")?;
+        assert_eq!((ProxyScheme::Socks5, "[::1]", 4447), (proxy.scheme, proxy.host.as_str(), proxy.port));
+        assert_eq!("socks5h://[::1]:9050", ProxyUrl::parse("socks5h://[::1]:9050")?.to_string());
+        for invalid in ["", "ftp://host:21", "http://:4444", "host:0", "host:port", "http://host:4444/path", "user@host"] {
+            assert!(ProxyUrl::parse(invalid).is_err(), "{}", invalid);
+        }
+        Ok(())
+    }
+}
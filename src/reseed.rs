@@ -0,0 +1,167 @@
+#![deny(missing_docs)]
+
+//! Reseeding the embedded router from a local bundle.
+//!
+//! A router without known peers reseeds over the network with the
+//!
+//! certificates in `certificates/reseed`. Air-gapped labs and private
+//!
+//! testnets can set `IS2FP_RESEED_BUNDLE` to an su3 or zip file instead,
+//!
+//! which is placed where the router picks it up at startup.
+
+use crate::{
+    error as is2fp_error,
+    router_config,
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use std::path::{
+    Path,
+    PathBuf,
+};
+
+/// Environment variable for the path of an su3 or zip reseed bundle
+pub const IS2FP_RESEED_BUNDLE: &str = "IS2FP_RESEED_BUNDLE";
+/// Router database of known peers, under the router directory
+const NETDB_DIR: &str = "netDb";
+/// Reseed signing certificates copied next to the binary
+const CERTIFICATES_DIR: &str = "certificates/reseed";
+/// Name the router looks for when reseeding at startup, plus extension
+const BUNDLE_NAME: &str = "i2pseeds";
+/// First bytes of an su3 file
+const SU3_MAGIC: &[u8] = b"I2Psu3";
+/// First bytes of a zip file
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+/// Where the router gets its first peers from
+#[derive(Debug, PartialEq)]
+pub enum ReseedSource {
+    /// The router knows this many peers, no reseed needed
+    KnownPeers(usize),
+    /// A local bundle, copied into the router directory
+    Bundle(PathBuf),
+    /// The reseed servers signed by the certificates directory
+    Network,
+    /// Nothing to bootstrap from
+    Unavailable,
+}
+
+/// Number of router infos in the router's database
+fn count_known_peers(netdb: &Path) -> usize {
+    let Ok(buckets) = std::fs::read_dir(netdb) else {
+        return 0;
+    };
+    buckets
+        .filter_map(Result::ok)
+        .filter_map(|bucket| std::fs::read_dir(bucket.path()).ok())
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|f| f.file_name().to_string_lossy().starts_with("routerInfo-"))
+        .count()
+}
+
+/// Extension the router expects for `bundle`, from its contents
+fn bundle_extension(bundle: &Path) -> Result<&'static str, is2fp_error::Ip2pError> {
+    let mut magic = [0u8; 6];
+    let read = std::fs::File::open(bundle).and_then(|mut f| std::io::Read::read_exact(&mut f, &mut magic));
+    if let Err(e) = read {
+        error!("failed to read reseed bundle {}: {}", bundle.display(), e);
+        return Err(is2fp_error::Ip2pError::I2P);
+    }
+    if magic.starts_with(SU3_MAGIC) {
+        Ok("su3")
+    } else if magic.starts_with(ZIP_MAGIC) {
+        Ok("zip")
+    } else {
+        error!("{} is not an su3 or zip reseed bundle", bundle.display());
+        Err(is2fp_error::Ip2pError::I2P)
+    }
+}
+
+/// Pick the reseed source for the router in `dir`, copying `bundle`
+///
+/// there when the router has no peers yet
+pub fn prepare_at(dir: &Path, bundle: Option<&Path>) -> Result<ReseedSource, is2fp_error::Ip2pError> {
+    let peers = count_known_peers(&dir.join(NETDB_DIR));
+    if peers > 0 {
+        if bundle.is_some() {
+            info!("router already knows {} peers, ignoring the reseed bundle", peers);
+        }
+        return Ok(ReseedSource::KnownPeers(peers));
+    }
+    if let Some(bundle) = bundle {
+        let target = dir.join(format!("{}.{}", BUNDLE_NAME, bundle_extension(bundle)?));
+        std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::copy(bundle, &target))
+            .map_err(|e| {
+                error!("failed to copy reseed bundle to {}: {}", target.display(), e);
+                is2fp_error::Ip2pError::I2P
+            })?;
+        info!("reseeding from {}", bundle.display());
+        return Ok(ReseedSource::Bundle(target));
+    }
+    let certificates = std::fs::read_dir(CERTIFICATES_DIR)
+        .map(|d| d.filter_map(Result::ok).count())
+        .unwrap_or_default();
+    if certificates > 0 {
+        return Ok(ReseedSource::Network);
+    }
+    Ok(ReseedSource::Unavailable)
+}
+
+/// Prepare reseeding for the embedded router. Logs what to fix when
+///
+/// it has no way to find its first peers.
+pub fn prepare() -> Result<ReseedSource, is2fp_error::Ip2pError> {
+    let config = router_config::get_path();
+    let dir = config.parent().map(Path::to_path_buf).unwrap_or_default();
+    let bundle = std::env::var(IS2FP_RESEED_BUNDLE).ok().filter(|b| !b.is_empty()).map(PathBuf::from);
+    let source = prepare_at(&dir, bundle.as_deref())?;
+    match &source {
+        ReseedSource::KnownPeers(peers) => info!("router knows {} peers", peers),
+        ReseedSource::Bundle(_) => (),
+        ReseedSource::Network => info!("no known peers, reseeding over the network"),
+        ReseedSource::Unavailable => {
+            warn!("no known peers and no reseed source, the router can't join the network");
+            warn!("copy the certificates directory next to is2fp for network reseeding,");
+            warn!("or set {} to an su3 or zip bundle", IS2FP_RESEED_BUNDLE);
+        }
+    }
+    Ok(source)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn prepare_test() -> Result<(), is2fp_error::Ip2pError> {
+        let dir = std::env::temp_dir().join(format!("is2fp-reseed-{}", rand::random::<u64>()));
+        let router = dir.join("router");
+        let bundle = dir.join("seeds.su3");
+        std::fs::create_dir_all(&dir).map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        std::fs::write(&bundle, b"I2Psu3\x00\x00 signed seeds").map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        // first start copies the bundle where the router looks for it
+        let target = router.join("i2pseeds.su3");
+        assert_eq!(ReseedSource::Bundle(target.clone()), prepare_at(&router, Some(&bundle))?);
+        assert!(target.exists());
+        // anything but su3 or zip is rejected
+        let text = dir.join("seeds.txt");
+        std::fs::write(&text, b"not a bundle").map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        assert!(prepare_at(&router, Some(&text)).is_err());
+        // once the router knows peers the bundle isn't needed
+        let bucket = router.join(NETDB_DIR).join("rA");
+        std::fs::create_dir_all(&bucket).map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        std::fs::write(bucket.join("routerInfo-A.dat"), b"").map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        assert_eq!(ReseedSource::KnownPeers(1), prepare_at(&router, Some(&bundle))?);
+        let _ = std::fs::remove_dir_all(&dir);
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+#![deny(missing_docs)]
+
+//! Retention and TTL expiry for the inbox, relays and fluff queue.
+
+use crate::{
+    error as is2fp_error,
+    inbox,
+    storage::{
+        SharedStorage,
+        Storage,
+    },
+    store::{
+        Namespace,
+        Store,
+    },
+    utils,
+};
+use log::info;
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// Environment variable for the seconds between retention runs
+pub const IS2FP_RETENTION_INTERVAL: &str = "IS2FP_RETENTION_INTERVAL";
+/// Default seconds between retention runs
+const DEFAULT_RETENTION_INTERVAL: u64 = 600;
+/// Seconds in a day
+const DAY: u64 = 86400;
+
+/// Maximum age in seconds and maximum number of entries to keep
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetentionPolicy {
+    /// Entries older than this many seconds are removed
+    pub max_age: u64,
+    /// Oldest entries beyond this count are removed
+    pub max_count: usize,
+}
+
+impl RetentionPolicy {
+    /// Keep every entry
+    pub const UNLIMITED: RetentionPolicy = RetentionPolicy { max_age: u64::MAX, max_count: usize::MAX };
+    /// Read `IS2FP_{NAME}_MAX_AGE` and `IS2FP_{NAME}_MAX_COUNT`, falling
+    ///
+    /// back to the defaults.
+    fn from_env(name: &str, default: RetentionPolicy) -> Self {
+        let max_age = utils::get_env_parse(&format!("IS2FP_{}_MAX_AGE", name)).unwrap_or(default.max_age);
+        let max_count = utils::get_env_parse(&format!("IS2FP_{}_MAX_COUNT", name)).unwrap_or(default.max_count);
+        RetentionPolicy { max_age, max_count }
+    }
+    /// Whether no limit is configured
+    fn is_unlimited(&self) -> bool {
+        *self == RetentionPolicy::UNLIMITED
+    }
+    /// Select entries to remove. `created` must be sorted oldest first.
+    fn expired(&self, now: u64, created: &[u64]) -> Vec<usize> {
+        let over_count = created.len().saturating_sub(self.max_count);
+        created
+            .iter()
+            .enumerate()
+            .filter(|(i, c)| *i < over_count || now.saturating_sub(**c) > self.max_age)
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+/// Retention policies for each namespace
+#[derive(Clone, Copy, Debug)]
+pub struct RetentionConfig {
+    /// Messages in the inbox
+    pub inbox: RetentionPolicy,
+    /// Relays learned via B32Exchange
+    pub relays: RetentionPolicy,
+    /// Messages waiting for fluff propagation
+    pub fluff: RetentionPolicy,
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            // messages are only removed when limits are configured
+            inbox: RetentionPolicy::UNLIMITED,
+            relays: RetentionPolicy { max_age: 7 * DAY, max_count: 1000 },
+            fluff: RetentionPolicy { max_age: 3600, max_count: 1000 },
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Policies from the environment, e.g. `IS2FP_INBOX_MAX_AGE`,
+    ///
+    /// `IS2FP_RELAY_MAX_COUNT` or `IS2FP_FLUFF_MAX_AGE`
+    pub fn from_env() -> Self {
+        let default: RetentionConfig = Default::default();
+        RetentionConfig {
+            inbox: RetentionPolicy::from_env("INBOX", default.inbox),
+            relays: RetentionPolicy::from_env("RELAY", default.relays),
+            fluff: RetentionPolicy::from_env("FLUFF", default.fluff),
+        }
+    }
+}
+
+/// Number of entries removed from each namespace
+#[derive(Debug, Default, PartialEq)]
+pub struct RetentionReport {
+    /// Messages removed from the inbox
+    pub inbox: usize,
+    /// Relays forgotten
+    pub relays: usize,
+    /// Messages dropped from the fluff queue
+    pub fluff: usize,
+}
+
+/// Prune every namespace once
+pub fn prune(s: &dyn Storage, config: &RetentionConfig) -> Result<RetentionReport, is2fp_error::Ip2pError> {
+    let now = utils::get_unix_time()?;
+    let mut report: RetentionReport = Default::default();
+    if !config.inbox.is_unlimited() {
+        report.inbox = prune_inbox(s, &config.inbox, now)?;
+    }
+    if !config.relays.is_unlimited() {
+        let mut relays: Vec<(String, utils::Relay)> = Store::scan(s, Namespace::Relay)?;
+        relays.sort_by_key(|(_, r)| r.seen);
+        let seen: Vec<u64> = relays.iter().map(|(_, r)| r.seen).collect();
+        for i in config.relays.expired(now, &seen) {
+            Store::delete(s, Namespace::Relay, &relays[i].0)?;
+            report.relays += 1;
+        }
+    }
+    if !config.fluff.is_unlimited() {
+        report.fluff = utils::prune_fluff(s, |fluff| {
+            let created: Vec<u64> = fluff.iter().map(|m| m.created).collect();
+            config.fluff.expired(now, &created)
+        })?;
+    }
+    Ok(report)
+}
+
+/// Remove expired inbox messages. Ids start with the creation time and
+///
+/// sort oldest first, so messages are selected by key without reading them.
+fn prune_inbox(s: &dyn Storage, policy: &RetentionPolicy, now: u64) -> Result<usize, is2fp_error::Ip2pError> {
+    let ids: Vec<(String, u64)> = inbox::ids(s)?
+        .into_iter()
+        .filter_map(|id| match inbox::created(&id) {
+            Some(created) => Some((id, created)),
+            None => {
+                log::warn!("skipping inbox message with unexpected id {}", id);
+                None
+            }
+        })
+        .collect();
+    let created: Vec<u64> = ids.iter().map(|(_, c)| *c).collect();
+    let expired = policy.expired(now, &created);
+    for i in &expired {
+        inbox::delete(s, &ids[*i].0)?;
+    }
+    Ok(expired.len())
+}
+
+/// Mark the relays of connected `peers` as seen now. Relays only send
+///
+/// a B32Exchange when they connect, so long-lived connections are
+///
+/// refreshed here to keep them from being pruned.
+pub fn refresh_relays(s: &dyn Storage, peers: &[String]) -> Result<(), is2fp_error::Ip2pError> {
+    let seen = utils::get_unix_time()?;
+    for peer in peers {
+        let relay: Option<utils::Relay> = Store::get(s, Namespace::Relay, peer)?;
+        if let Some(relay) = relay {
+            Store::put(s, Namespace::Relay, peer, &utils::Relay { seen, ..relay })?;
+        }
+    }
+    Ok(())
+}
+
+/// Seconds between retention runs from `IS2FP_RETENTION_INTERVAL`
+fn get_interval() -> Duration {
+    Duration::from_secs(utils::get_env_parse(IS2FP_RETENTION_INTERVAL).unwrap_or(DEFAULT_RETENTION_INTERVAL))
+}
+
+/// Background retention task. Runs until shutdown.
+pub async fn run(s: SharedStorage, mut shutdown: watch::Receiver<bool>) {
+    let config = RetentionConfig::from_env();
+    info!("retention policies: {:?}", config);
+    let mut interval = tokio::time::interval(get_interval());
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.changed() => return,
+        }
+        match prune(s.as_ref(), &config) {
+            Ok(report) => info!(
+                "retention removed {} inbox messages, {} relays, {} fluff messages",
+                report.inbox, report.relays, report.fluff
+            ),
+            Err(e) => log::error!("retention failed: {:?}", e),
+        }
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn expired_test() {
+        let policy = RetentionPolicy { max_age: 100, max_count: 2 };
+        // oldest first: one too old, one over the count
+        let created = [10, 950, 960, 970];
+        assert_eq!(vec![0, 1], policy.expired(1000, &created));
+        let policy = RetentionPolicy { max_age: 1000, max_count: 10 };
+        assert!(policy.expired(1000, &created).is_empty());
+        assert!(RetentionPolicy::UNLIMITED.expired(u64::MAX, &created).is_empty());
+    }
+
+    #[test]
+    fn prune_inbox_test() -> Result<(), is2fp_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        for (created, mid) in [(10, "a"), (20, "b"), (30, "c")] {
+            inbox::save(&s, &utils::Message { created, mid: String::from(mid), ..Default::default() })?;
+        }
+        // values aren't decoded, only ids are read
+        s.write(&Namespace::Inbox.key(&format!("{:020}-d", 40)), &[0xff])
+            .map_err(is2fp_error::Ip2pError::Database)?;
+        let config = RetentionConfig {
+            inbox: RetentionPolicy { max_age: u64::MAX, max_count: 2 },
+            ..Default::default()
+        };
+        assert_eq!(2, prune(&s, &config)?.inbox);
+        assert_eq!(vec![format!("{:020}-c", 30), format!("{:020}-d", 40)], inbox::ids(&s)?);
+        // the default keeps every message
+        assert_eq!(0, prune(&s, &Default::default())?.inbox);
+        assert_eq!(2, inbox::count(&s)?);
+        Ok(())
+    }
+
+    #[test]
+    fn refresh_relays_test() -> Result<(), is2fp_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        let stale = utils::Relay { b32: String::from("test.b32.i2p"), seen: 1 };
+        Store::put(&s, Namespace::Relay, "connected", &stale)?;
+        Store::put(&s, Namespace::Relay, "gone", &stale)?;
+        refresh_relays(&s, &[String::from("connected"), String::from("unknown")])?;
+        let report = prune(&s, &Default::default())?;
+        assert_eq!(1, report.relays);
+        let relays: Vec<(String, utils::Relay)> = Store::scan(&s, Namespace::Relay)?;
+        assert_eq!(vec![String::from("connected")], relays.into_iter().map(|(k, _)| k).collect::<Vec<_>>());
+        Ok(())
+    }
+}
@@ -0,0 +1,302 @@
+#![deny(missing_docs)]
+
+//! Rotation of the relay destination.
+//!
+//! A new destination replaces the current one on a schedule or when
+//!
+//! requested from the console. The old destination keeps accepting
+//!
+//! messages for a grace period while peers learn the new b32 address.
+
+use crate::{
+    backend::{
+        Destination,
+        I2pBackend,
+        SharedBackend,
+    },
+    error as is2fp_error,
+    i2p,
+    storage::{
+        SharedStorage,
+        Storage,
+    },
+    store::{
+        Namespace,
+        Store,
+    },
+    utils,
+};
+use lazy_static::lazy_static;
+use log::{
+    error,
+    info,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::{
+    watch,
+    Notify,
+};
+
+/// Environment variable for the seconds between rotations, 0 disables them
+pub const IS2FP_ROTATION_INTERVAL: &str = "IS2FP_ROTATION_INTERVAL";
+/// Environment variable for the seconds old destinations keep accepting
+pub const IS2FP_ROTATION_GRACE: &str = "IS2FP_ROTATION_GRACE";
+/// LMDB key for when the current destination was created
+pub const APP_DEST_CREATED: &str = "app-dest-created";
+/// LMDB key for destinations in their grace period
+pub const APP_RETIRED_DESTS: &str = "app-retired-dests";
+/// Default seconds between rotations
+const DEFAULT_ROTATION_INTERVAL: u64 = 7 * 86400;
+/// Default grace period in seconds
+const DEFAULT_ROTATION_GRACE: u64 = 86400;
+/// Seconds between schedule checks
+const ROTATION_CHECK_INTERVAL: u64 = 60;
+
+lazy_static! {
+    /// Held while the relay destination is replaced or its tunnels are
+    ///
+    /// restarted, so neither acts on a destination the other is changing
+    pub static ref DESTINATION_LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::new(());
+}
+
+/// A replaced destination and when it was replaced
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RetiredDestination {
+    /// The old destination
+    pub dest: Destination,
+    /// Unix time of the rotation
+    pub retired: u64,
+}
+
+/// Rotation schedule
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RotationConfig {
+    /// Seconds between rotations, 0 disables scheduled rotation
+    pub interval: u64,
+    /// Seconds old destinations keep accepting
+    pub grace: u64,
+}
+
+impl RotationConfig {
+    /// Schedule from `IS2FP_ROTATION_INTERVAL` and `IS2FP_ROTATION_GRACE`
+    pub fn from_env() -> Self {
+        let read = |name: &str, default: u64| utils::get_env_parse(name).unwrap_or(default);
+        RotationConfig {
+            interval: read(IS2FP_ROTATION_INTERVAL, DEFAULT_ROTATION_INTERVAL),
+            grace: read(IS2FP_ROTATION_GRACE, DEFAULT_ROTATION_GRACE),
+        }
+    }
+    /// Whether a destination created at `created` is due for rotation
+    fn is_due(&self, now: u64, created: u64) -> bool {
+        self.interval > 0 && now.saturating_sub(created) >= self.interval
+    }
+}
+
+/// Manual rotation requests and announcements of the new b32 address
+#[derive(Debug)]
+pub struct Rotation {
+    requested: Notify,
+    announce: watch::Sender<String>,
+}
+
+/// Rotation handle shared between the console and the rotation task
+pub type SharedRotation = Arc<Rotation>;
+
+impl Default for Rotation {
+    fn default() -> Self {
+        let (announce, _) = watch::channel(String::new());
+        Rotation { requested: Notify::new(), announce }
+    }
+}
+
+impl Rotation {
+    /// Rotate as soon as possible
+    pub fn request(&self) {
+        self.requested.notify_one();
+    }
+    /// Receiver of each new b32 address
+    pub fn subscribe(&self) -> watch::Receiver<String> {
+        self.announce.subscribe()
+    }
+}
+
+/// Destinations in their grace period
+pub fn get_retired(s: &dyn Storage) -> Result<Vec<RetiredDestination>, is2fp_error::Ip2pError> {
+    let retired: Option<Vec<RetiredDestination>> = Store::get(s, Namespace::Settings, APP_RETIRED_DESTS)?;
+    Ok(retired.unwrap_or_default())
+}
+
+/// Replace the current destination with a new one. The old one keeps
+///
+/// accepting until `expire` removes it. The new keys are stored in one
+///
+/// write, callers running next to the health supervisor hold `DESTINATION_LOCK`.
+pub fn rotate(s: &dyn Storage, backend: &dyn I2pBackend) -> Result<Destination, is2fp_error::Ip2pError> {
+    let now = utils::get_unix_time()?;
+    let app_sk: Option<String> = Store::get(s, Namespace::Settings, i2p::APP_I2P_SK)?;
+    let dest = backend.create_destination()?;
+    backend.start_server_tunnel(&dest, utils::get_app_port())?;
+    let mut retired = get_retired(s)?;
+    if let Some(sk) = app_sk.filter(|sk| !sk.is_empty()) {
+        let old = Destination { b32: i2p::get_destination(s)?, sk };
+        retired.push(RetiredDestination { dest: old, retired: now });
+    }
+    let entries = vec![
+        Store::entry(Namespace::Settings, APP_RETIRED_DESTS, &retired)?,
+        Store::entry(Namespace::Settings, i2p::APP_B32_DEST, &dest.b32)?,
+        Store::entry(Namespace::Settings, i2p::APP_I2P_SK, &dest.sk)?,
+        Store::entry(Namespace::Settings, APP_DEST_CREATED, &now)?,
+    ];
+    Store::put_all(s, &entries)?;
+    info!("rotated relay server address to {}", &dest.b32);
+    Ok(dest)
+}
+
+/// Stop destinations whose grace period is over. Returns how many were stopped.
+pub fn expire(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+    config: &RotationConfig,
+) -> Result<usize, is2fp_error::Ip2pError> {
+    let now = utils::get_unix_time()?;
+    let (expired, active): (Vec<RetiredDestination>, Vec<RetiredDestination>) = get_retired(s)?
+        .into_iter()
+        .partition(|r| now.saturating_sub(r.retired) > config.grace);
+    for r in &expired {
+        info!("grace period for {} is over", &r.dest.b32);
+        backend
+            .stop_server_tunnel(&r.dest)
+            .unwrap_or_else(|e| error!("failed to stop {}: {:?}", &r.dest.b32, e));
+    }
+    if !expired.is_empty() {
+        Store::put(s, Namespace::Settings, APP_RETIRED_DESTS, &active)?;
+    }
+    Ok(expired.len())
+}
+
+/// Publish retired destinations still in their grace period after a restart
+pub fn restore(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+    config: &RotationConfig,
+) -> Result<(), is2fp_error::Ip2pError> {
+    expire(s, backend, config)?;
+    for r in get_retired(s)? {
+        backend.start_server_tunnel(&r.dest, utils::get_app_port())?;
+    }
+    Ok(())
+}
+
+/// Rotate if requested or due, then expire old destinations
+fn check(
+    s: &dyn Storage,
+    backend: &dyn I2pBackend,
+    config: &RotationConfig,
+    requested: bool,
+) -> Result<Option<Destination>, is2fp_error::Ip2pError> {
+    let now = utils::get_unix_time()?;
+    let created: Option<u64> = Store::get(s, Namespace::Settings, APP_DEST_CREATED)?;
+    let dest = match created {
+        // destinations created before rotation existed start their interval now
+        None if !requested => {
+            Store::put(s, Namespace::Settings, APP_DEST_CREATED, &now)?;
+            None
+        }
+        Some(created) if !requested && !config.is_due(now, created) => None,
+        _ => Some(rotate(s, backend)?),
+    };
+    expire(s, backend, config)?;
+    Ok(dest)
+}
+
+/// Background rotation task. Runs until shutdown.
+pub async fn run(
+    s: SharedStorage,
+    backend: SharedBackend,
+    rotation: SharedRotation,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let config = RotationConfig::from_env();
+    info!("destination rotation: {:?}", config);
+    let mut interval = tokio::time::interval(Duration::from_secs(ROTATION_CHECK_INTERVAL));
+    loop {
+        let requested = tokio::select! {
+            _ = interval.tick() => false,
+            _ = rotation.requested.notified() => true,
+            _ = shutdown.changed() => return,
+        };
+        let status = i2p::check_connection(s.as_ref()).await.unwrap_or_default();
+        if status.state != i2p::Lifecycle::Ready {
+            if requested {
+                error!("can't rotate while i2p is {:?}", status.state);
+            }
+            continue;
+        }
+        // backend calls block until tunnels are built
+        let (task_storage, task_backend) = (s.clone(), backend.clone());
+        let guard = DESTINATION_LOCK.lock().await;
+        let result = tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            check(task_storage.as_ref(), task_backend.as_ref(), &config, requested)
+        })
+        .await;
+        match result {
+            Ok(Ok(Some(dest))) => {
+                rotation.announce.send_replace(dest.b32);
+            }
+            Ok(Ok(None)) => (),
+            Ok(Err(e)) => error!("destination rotation failed: {:?}", e),
+            Err(e) => error!("destination rotation task failed: {:?}", e),
+        }
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        backend::TestBackend,
+        storage::MemoryStorage,
+    };
+
+    #[test]
+    fn rotate_test() -> Result<(), is2fp_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        let backend = TestBackend::default();
+        let first = rotate(&s, &backend)?;
+        let second = rotate(&s, &backend)?;
+        assert_eq!(second.b32, i2p::get_destination(&s)?);
+        // the old destination keeps accepting during the grace period
+        let config = RotationConfig { interval: 0, grace: 3600 };
+        assert_eq!(0, expire(&s, &backend, &config)?);
+        assert_eq!(vec![first.b32.clone(), second.b32.clone()], *backend.published.lock().unwrap());
+        assert_eq!(first, get_retired(&s)?[0].dest);
+        // and stops once it is over
+        let mut retired = get_retired(&s)?;
+        retired[0].retired = 0;
+        Store::put(&s, Namespace::Settings, APP_RETIRED_DESTS, &retired)?;
+        assert_eq!(1, expire(&s, &backend, &config)?);
+        assert_eq!(vec![second.b32], *backend.published.lock().unwrap());
+        assert!(get_retired(&s)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn is_due_test() {
+        let config = RotationConfig { interval: 100, grace: 10 };
+        assert!(!config.is_due(150, 100));
+        assert!(config.is_due(200, 100));
+        let disabled = RotationConfig { interval: 0, grace: 10 };
+        assert!(!disabled.is_due(u64::MAX, 0));
+    }
+}
@@ -0,0 +1,277 @@
+#![deny(missing_docs)]
+
+//! Reader and writer for the I2P `router.config` file.
+//!
+//! The file holds `key=value` lines. Comments, blank lines and the order
+//!
+//! of entries are kept when the file is written back.
+
+use crate::error as is2fp_error;
+use log::{
+    error,
+    info,
+    warn,
+};
+use std::{
+    fmt,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+
+/// Environment variable for the path of `router.config`
+pub const IS2FP_ROUTER_CONFIG: &str = "IS2FP_ROUTER_CONFIG";
+/// Environment variable for router options set before start, e.g.
+///
+/// `i2np.bandwidth.inboundKBytesPerSecond=256,i2np.udp.port=12345`
+pub const IS2FP_ROUTER_OPTIONS: &str = "IS2FP_ROUTER_OPTIONS";
+/// File name of the router configuration
+const ROUTER_CONFIG: &str = "router.config";
+/// UDP (SSU) port
+pub const UDP_PORT: &str = "i2np.udp.port";
+/// TCP (NTCP2) port
+pub const NTCP_PORT: &str = "i2np.ntcp.port";
+/// Inbound bandwidth limit in KBps
+pub const INBOUND_BANDWIDTH: &str = "i2np.bandwidth.inboundKBytesPerSecond";
+/// Outbound bandwidth limit in KBps
+pub const OUTBOUND_BANDWIDTH: &str = "i2np.bandwidth.outboundKBytesPerSecond";
+
+/// A line of the configuration file
+#[derive(Clone, Debug, PartialEq)]
+enum Line {
+    Entry { key: String, value: String },
+    /// Comments, blank and malformed lines, written back as-is
+    Other(String),
+}
+
+/// Parsed `router.config`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouterConfig {
+    lines: Vec<Line>,
+}
+
+impl RouterConfig {
+    /// Parse configuration text. Malformed lines are kept but ignored.
+    pub fn parse(text: &str) -> Self {
+        let lines = text
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim();
+                if trimmed.is_empty() || trimmed.starts_with('#') {
+                    return Line::Other(String::from(line));
+                }
+                match trimmed.split_once('=') {
+                    Some((key, value)) if !key.trim().is_empty() => Line::Entry {
+                        key: String::from(key.trim()),
+                        value: String::from(value.trim()),
+                    },
+                    _ => {
+                        warn!("ignoring malformed router.config line: {}", line);
+                        Line::Other(String::from(line))
+                    }
+                }
+            })
+            .collect();
+        RouterConfig { lines }
+    }
+    /// Read the configuration at `path`. A missing file is empty.
+    pub fn load(path: &Path) -> Result<Self, is2fp_error::Ip2pError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Ok(RouterConfig::parse(&text)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Default::default()),
+            Err(e) => {
+                error!("failed to read {}: {}", path.display(), e);
+                Err(is2fp_error::Ip2pError::I2P)
+            }
+        }
+    }
+    /// Write the configuration to `path`, replacing the file atomically
+    pub fn save(&self, path: &Path) -> Result<(), is2fp_error::Ip2pError> {
+        let tmp = path.with_extension("config.tmp");
+        std::fs::write(&tmp, self.to_string())
+            .and_then(|_| std::fs::rename(&tmp, path))
+            .map_err(|e| {
+                error!("failed to write {}: {}", path.display(), e);
+                is2fp_error::Ip2pError::I2P
+            })
+    }
+    /// Value of `key`. The last entry wins, as in the router.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.lines.iter().rev().find_map(|line| match line {
+            Line::Entry { key: k, value } if k == key => Some(value.as_str()),
+            _ => None,
+        })
+    }
+    /// Set `key`, replacing existing entries in place or appending it
+    pub fn set(&mut self, key: &str, value: &str) {
+        let mut found = false;
+        self.lines.retain_mut(|line| match line {
+            Line::Entry { key: k, value: v } if k == key => {
+                if found {
+                    return false;
+                }
+                *v = String::from(value);
+                found = true;
+                true
+            }
+            _ => true,
+        });
+        if !found {
+            self.lines.push(Line::Entry { key: String::from(key), value: String::from(value) });
+        }
+    }
+    /// Remove every entry for `key`
+    pub fn remove(&mut self, key: &str) {
+        self.lines.retain(|line| !matches!(line, Line::Entry { key: k, .. } if k == key));
+    }
+    /// Set every `key=value` pair of a comma separated list. Nothing is
+    ///
+    /// set if any entry is malformed.
+    pub fn set_options(&mut self, options: &str) -> Result<(), is2fp_error::Ip2pError> {
+        let mut entries: Vec<(&str, &str)> = Vec::new();
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            match option.split_once('=') {
+                Some((key, value)) if !key.trim().is_empty() => entries.push((key.trim(), value.trim())),
+                _ => {
+                    error!("malformed router option, expected key=value: {}", option);
+                    return Err(is2fp_error::Ip2pError::I2P);
+                }
+            }
+        }
+        for (key, value) in entries {
+            self.set(key, value);
+        }
+        Ok(())
+    }
+    /// Parse the value of `key` as a port
+    fn get_port(&self, key: &str) -> Option<u16> {
+        self.get(key).and_then(|v| v.parse::<u16>().ok()).filter(|p| *p != 0)
+    }
+    /// External UDP port assigned to the router
+    pub fn udp_port(&self) -> Option<u16> {
+        self.get_port(UDP_PORT)
+    }
+    /// External TCP port assigned to the router
+    pub fn ntcp_port(&self) -> Option<u16> {
+        self.get_port(NTCP_PORT)
+    }
+    /// Limit inbound and outbound bandwidth in KBps
+    pub fn set_bandwidth(&mut self, inbound: u32, outbound: u32) {
+        self.set(INBOUND_BANDWIDTH, &inbound.to_string());
+        self.set(OUTBOUND_BANDWIDTH, &outbound.to_string());
+    }
+}
+
+impl fmt::Display for RouterConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for line in &self.lines {
+            match line {
+                Line::Entry { key, value } => writeln!(f, "{}={}", key, value)?,
+                Line::Other(other) => writeln!(f, "{}", other)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Path of `router.config` in the working directory, where the embedded
+///
+/// router keeps its files
+fn get_embedded_path() -> PathBuf {
+    std::env::current_dir().unwrap_or_default().join(ROUTER_CONFIG)
+}
+
+/// Path of the `router.config` is2fp reads and writes, from
+///
+/// `IS2FP_ROUTER_CONFIG`. Defaults to the file of the embedded router.
+///
+/// Setting it doesn't move the embedded router's configuration, it's for
+///
+/// routers started outside is2fp.
+pub fn get_path() -> PathBuf {
+    match std::env::var(IS2FP_ROUTER_CONFIG) {
+        Ok(path) if !path.is_empty() => PathBuf::from(path),
+        _ => get_embedded_path(),
+    }
+}
+
+/// Write `IS2FP_ROUTER_OPTIONS` to `router.config`. Call before the
+///
+/// embedded router starts, it only reads the file at startup.
+pub fn apply_env() -> Result<(), is2fp_error::Ip2pError> {
+    let path = get_path();
+    if path != get_embedded_path() {
+        warn!(
+            "the embedded router reads {}, not {} from {}",
+            get_embedded_path().display(),
+            path.display(),
+            IS2FP_ROUTER_CONFIG
+        );
+    }
+    let options = std::env::var(IS2FP_ROUTER_OPTIONS).unwrap_or_default();
+    if options.is_empty() {
+        return Ok(());
+    }
+    let mut config = RouterConfig::load(&path)?;
+    config.set_options(&options)?;
+    info!("writing router options to {}", path.display());
+    config.save(&path)
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    const SAMPLE: &str = "# NOTE: This I2P config file must use UTF-8 encoding\n\
+        i2np.udp.port=24680\n\
+        \n\
+        malformed line\n\
+        i2np.ntcp.port = 0\n\
+        router.sharePercentage=80\n";
+
+    #[test]
+    fn parse_test() {
+        let config = RouterConfig::parse(SAMPLE);
+        assert_eq!(Some(24680), config.udp_port());
+        assert_eq!(None, config.ntcp_port());
+        assert_eq!(Some("80"), config.get("router.sharePercentage"));
+        assert_eq!(None, config.get("malformed line"));
+        // comments and malformed lines survive a round trip
+        let text = config.to_string();
+        assert!(text.starts_with("# NOTE"));
+        assert!(text.contains("malformed line\n"));
+        assert_eq!(config, RouterConfig::parse(&text));
+    }
+
+    #[test]
+    fn set_test() -> Result<(), is2fp_error::Ip2pError> {
+        let mut config = RouterConfig::parse(SAMPLE);
+        config.set(UDP_PORT, "13579");
+        config.set_bandwidth(256, 128);
+        // malformed lists are rejected as a whole
+        assert!(config.set_options("router.sharePercentage=50, bad, a.b=c").is_err());
+        assert!(config.set_options("=x").is_err());
+        assert_eq!(Some("80"), config.get("router.sharePercentage"));
+        config.set_options("router.sharePercentage=50, a.b=c,")?;
+        config.remove(NTCP_PORT);
+        assert_eq!(Some(13579), config.udp_port());
+        assert_eq!(Some("256"), config.get(INBOUND_BANDWIDTH));
+        assert_eq!(Some("50"), config.get("router.sharePercentage"));
+        assert_eq!(Some("c"), config.get("a.b"));
+        assert_eq!(None, config.get(NTCP_PORT));
+        let path = std::env::temp_dir().join(format!("is2fp-router-{}.config", rand::random::<u64>()));
+        config.save(&path)?;
+        let loaded = RouterConfig::load(&path)?;
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(config, loaded);
+        assert_eq!(
+            RouterConfig::default(),
+            RouterConfig::load(&path.with_extension("missing"))?
+        );
+        Ok(())
+    }
+}
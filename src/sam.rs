@@ -0,0 +1,674 @@
+#![deny(missing_docs)]
+
+//! Native SAM v3 client backend.
+//!
+//! Talks to the SAM bridge of an external router (Java I2P or i2pd) instead
+//!
+//! of embedding one. See <https://geti2p.net/en/docs/api/samv3>.
+
+use crate::{
+    backend::{
+        Destination,
+        I2pBackend,
+    },
+    error as is2fp_error,
+    tunnel_config::{
+        TunnelConfig,
+        TunnelOptions,
+    },
+};
+use futures::future::BoxFuture;
+use log::{
+    debug,
+    error,
+    info,
+};
+use sha2::{
+    Digest,
+    Sha256,
+};
+use std::{
+    collections::HashMap,
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    net::{
+        TcpStream,
+        ToSocketAddrs,
+    },
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::io::{
+    AsyncBufReadExt,
+    AsyncReadExt,
+    AsyncWriteExt,
+};
+
+/// Environment variable for the SAM bridge address
+pub const IS2FP_SAM_ADDRESS: &str = "IS2FP_SAM_ADDRESS";
+/// Default SAM bridge address
+pub const DEFAULT_SAM_ADDRESS: &str = "127.0.0.1:7656";
+/// Handshake sent on every new SAM connection
+const HELLO: &str = "HELLO VERSION MIN=3.1 MAX=3.3";
+/// Ed25519 signatures for new destinations
+const SIGNATURE_TYPE: u8 = 7;
+/// I2P uses base 64 with `-` and `~` instead of `+` and `/`
+const I2P_BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-~";
+const BASE32: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+/// Bytes of the public and signing keys at the start of a destination
+const DESTINATION_KEYS_LENGTH: usize = 384;
+/// Seconds to wait for a connection to the bridge
+const SAM_CONNECT_TIMEOUT: u64 = 10;
+/// Seconds to wait for a reply, session creation waits for its tunnels
+const SAM_REPLY_TIMEOUT: u64 = 120;
+
+/// Backend for routers with a SAM v3 bridge
+pub struct SamBackend {
+    address: String,
+    /// Control sockets and the destination they serve, empty for the
+    ///
+    /// shared client session and the session id for other clients.
+    ///
+    /// Sessions close when their socket does.
+    sessions: Mutex<Vec<(String, TcpStream)>>,
+    /// Id of the session used for outbound streams
+    client_id: Mutex<Option<String>>,
+    /// Options for the sessions' tunnels
+    tunnels: TunnelConfig,
+}
+
+impl SamBackend {
+    /// Backend for the bridge at `address`, e.g. `127.0.0.1:7656`
+    pub fn new(address: &str) -> Self {
+        SamBackend {
+            address: String::from(address),
+            sessions: Mutex::new(Vec::new()),
+            client_id: Mutex::new(None),
+            tunnels: Default::default(),
+        }
+    }
+    /// Build session tunnels with `tunnels` instead of the defaults
+    pub fn with_tunnels(mut self, tunnels: TunnelConfig) -> Self {
+        self.tunnels = tunnels;
+        self
+    }
+    /// Backend for the bridge at `IS2FP_SAM_ADDRESS` with the tunnel
+    ///
+    /// options from the environment
+    pub fn from_env() -> Result<Self, is2fp_error::Ip2pError> {
+        let address = std::env::var(IS2FP_SAM_ADDRESS).unwrap_or_default();
+        let backend = if address.is_empty() {
+            SamBackend::new(DEFAULT_SAM_ADDRESS)
+        } else {
+            SamBackend::new(&address)
+        };
+        Ok(backend.with_tunnels(TunnelConfig::from_env()?))
+    }
+    /// Open a control connection and complete the handshake
+    fn connect(&self) -> Result<BufReader<TcpStream>, is2fp_error::Ip2pError> {
+        let connect_error = |e: std::io::Error| {
+            error!("failed to connect to SAM bridge at {}: {}", self.address, e);
+            is2fp_error::Ip2pError::I2P
+        };
+        let address = self
+            .address
+            .to_socket_addrs()
+            .map_err(connect_error)?
+            .next()
+            .ok_or(is2fp_error::Ip2pError::I2P)?;
+        let stream = TcpStream::connect_timeout(&address, Duration::from_secs(SAM_CONNECT_TIMEOUT))
+            .map_err(connect_error)?;
+        let timeout = Some(Duration::from_secs(SAM_REPLY_TIMEOUT));
+        stream
+            .set_read_timeout(timeout)
+            .and_then(|_| stream.set_write_timeout(timeout))
+            .map_err(connect_error)?;
+        let mut conn = BufReader::new(stream);
+        command(&mut conn, HELLO)?;
+        Ok(conn)
+    }
+    /// Create a stream session and keep its control socket open
+    fn create_session(
+        &self,
+        id: &str,
+        b32: &str,
+        destination: &str,
+        options: &TunnelOptions,
+    ) -> Result<(), is2fp_error::Ip2pError> {
+        let mut conn = self.connect()?;
+        command(
+            &mut conn,
+            &format!(
+                "SESSION CREATE STYLE=STREAM ID={} DESTINATION={} SIGNATURE_TYPE={} {}",
+                id,
+                destination,
+                SIGNATURE_TYPE,
+                options.to_i2cp()
+            ),
+        )?;
+        self.keep(b32, conn)
+    }
+    fn keep(&self, b32: &str, conn: BufReader<TcpStream>) -> Result<(), is2fp_error::Ip2pError> {
+        self.sessions
+            .lock()
+            .map_err(|_| is2fp_error::Ip2pError::I2P)?
+            .push((String::from(b32), conn.into_inner()));
+        Ok(())
+    }
+    /// Create the shared client session. Returns once the router has
+    ///
+    /// built its tunnels.
+    fn start_client(&self) -> Result<(), is2fp_error::Ip2pError> {
+        let id = format!("is2fp-client-{}", rand::random::<u32>());
+        self.create_session(&id, "", "TRANSIENT", &self.tunnels.client)?;
+        *self.client_id.lock().map_err(|_| is2fp_error::Ip2pError::I2P)? = Some(id);
+        Ok(())
+    }
+    fn get_client_id(&self) -> Result<String, is2fp_error::Ip2pError> {
+        let id = self.client_id.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        id.clone().ok_or_else(|| {
+            error!("SAM client session not started");
+            is2fp_error::Ip2pError::I2P
+        })
+    }
+}
+
+impl I2pBackend for SamBackend {
+    fn name(&self) -> &'static str {
+        "sam"
+    }
+    fn start_router(&self) -> Result<(), is2fp_error::Ip2pError> {
+        info!("connecting to SAM bridge at {}", self.address);
+        self.start_client()
+    }
+    fn is_running(&self) -> bool {
+        // the bridge answers as long as the router is up
+        self.connect().is_ok()
+    }
+    fn create_destination(&self) -> Result<Destination, is2fp_error::Ip2pError> {
+        let mut conn = self.connect()?;
+        let reply = command(&mut conn, &format!("DEST GENERATE SIGNATURE_TYPE={}", SIGNATURE_TYPE))?;
+        let public = reply.get("PUB").ok_or(is2fp_error::Ip2pError::I2P)?;
+        let sk = reply.get("PRIV").ok_or(is2fp_error::Ip2pError::I2P)?;
+        Ok(Destination {
+            b32: destination_b32(public)?,
+            sk: String::from(sk),
+        })
+    }
+    fn check_destination(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        let b32 = private_key_b32(&dest.sk)?;
+        if b32 != dest.b32 {
+            error!("secret key belongs to {}, not {}", b32, dest.b32);
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+        Ok(())
+    }
+    fn start_server_tunnel(&self, dest: &Destination, port: u16) -> Result<(), is2fp_error::Ip2pError> {
+        let id = format!("is2fp-server-{}", rand::random::<u32>());
+        self.create_session(&id, &dest.b32, &dest.sk, &self.tunnels.server)?;
+        let mut conn = self.connect()?;
+        command(
+            &mut conn,
+            &format!("STREAM FORWARD ID={} PORT={} HOST=127.0.0.1 SILENT=true", id, port),
+        )?;
+        self.keep(&dest.b32, conn)?;
+        info!("forwarding {} to port {}", dest.b32, port);
+        Ok(())
+    }
+    fn stop_server_tunnel(&self, dest: &Destination) -> Result<(), is2fp_error::Ip2pError> {
+        let mut sessions = self.sessions.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        for (_, session) in sessions.iter().filter(|(b32, _)| b32 == &dest.b32) {
+            let _ = session.shutdown(std::net::Shutdown::Both);
+        }
+        sessions.retain(|(b32, _)| b32 != &dest.b32);
+        Ok(())
+    }
+    fn check_proxy(&self) -> bool {
+        // streams are opened on the client session through the bridge
+        self.get_client_id().is_ok() && self.is_running()
+    }
+    fn restart_proxy(&self) -> Result<(), is2fp_error::Ip2pError> {
+        self.close_client("")?;
+        self.start_client()
+    }
+    fn stop(&self) -> Result<(), is2fp_error::Ip2pError> {
+        // closing the control sockets ends the sessions, the router keeps running
+        let mut sessions = self.sessions.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        for (_, session) in sessions.drain(..) {
+            let _ = session.shutdown(std::net::Shutdown::Both);
+        }
+        *self.client_id.lock().map_err(|_| is2fp_error::Ip2pError::I2P)? = None;
+        Ok(())
+    }
+    fn open_client(&self) -> Result<String, is2fp_error::Ip2pError> {
+        let id = format!("is2fp-stem-{}", rand::random::<u32>());
+        self.create_session(&id, &id, "TRANSIENT", &self.tunnels.client)?;
+        Ok(id)
+    }
+    fn close_client(&self, client: &str) -> Result<(), is2fp_error::Ip2pError> {
+        let mut sessions = self.sessions.lock().map_err(|_| is2fp_error::Ip2pError::I2P)?;
+        for (_, session) in sessions.iter().filter(|(id, _)| id == client) {
+            let _ = session.shutdown(std::net::Shutdown::Both);
+        }
+        sessions.retain(|(id, _)| id != client);
+        Ok(())
+    }
+    fn post_json<'a>(
+        &'a self,
+        client: Option<&'a str>,
+        b32: &'a str,
+        path: &'a str,
+        body: Vec<u8>,
+    ) -> BoxFuture<'a, Result<Vec<u8>, is2fp_error::Ip2pError>> {
+        Box::pin(async move {
+            let id = match client {
+                Some(id) => String::from(id),
+                None => self.get_client_id()?,
+            };
+            let connect = tokio::net::TcpStream::connect(&self.address);
+            let stream = tokio::time::timeout(Duration::from_secs(SAM_CONNECT_TIMEOUT), connect)
+                .await
+                .map_err(|_| {
+                    error!("timed out connecting to SAM bridge at {}", self.address);
+                    is2fp_error::Ip2pError::I2P
+                })?
+                .map_err(|e| {
+                    error!("failed to connect to SAM bridge at {}: {}", self.address, e);
+                    is2fp_error::Ip2pError::I2P
+                })?;
+            let mut conn = tokio::io::BufReader::new(stream);
+            command_async(&mut conn, HELLO).await?;
+            let lookup = command_async(&mut conn, &format!("NAMING LOOKUP NAME={}", b32)).await?;
+            let destination = lookup.get("VALUE").ok_or(is2fp_error::Ip2pError::I2P)?;
+            command_async(
+                &mut conn,
+                &format!("STREAM CONNECT ID={} DESTINATION={} SILENT=false", id, destination),
+            )
+            .await?;
+            // HTTP/1.0 so the response is never chunked
+            let mut request = format!(
+                "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+                path,
+                b32,
+                body.len()
+            )
+            .into_bytes();
+            request.extend_from_slice(&body);
+            let io_error = |e: std::io::Error| {
+                error!("SAM stream to {} failed: {}", b32, e);
+                is2fp_error::Ip2pError::I2P
+            };
+            conn.get_mut().write_all(&request).await.map_err(io_error)?;
+            let mut response: Vec<u8> = Vec::new();
+            tokio::time::timeout(Duration::from_secs(SAM_REPLY_TIMEOUT), conn.read_to_end(&mut response))
+                .await
+                .map_err(|_| {
+                    error!("SAM stream to {} timed out", b32);
+                    is2fp_error::Ip2pError::I2P
+                })?
+                .map_err(io_error)?;
+            http_body(&response)
+        })
+    }
+}
+
+/// Send a command and parse the reply. Fails unless `RESULT=OK`.
+fn command(
+    conn: &mut BufReader<TcpStream>,
+    cmd: &str,
+) -> Result<HashMap<String, String>, is2fp_error::Ip2pError> {
+    debug!("SAM: {}", redact(cmd));
+    let mut line = String::new();
+    let read = conn
+        .get_mut()
+        .write_all(format!("{}\n", cmd).as_bytes())
+        .and_then(|_| conn.read_line(&mut line))
+        .map_err(|e| {
+            error!("SAM bridge connection failed: {}", e);
+            is2fp_error::Ip2pError::I2P
+        })?;
+    if read == 0 {
+        error!("SAM bridge closed the connection");
+        return Err(is2fp_error::Ip2pError::I2P);
+    }
+    parse_reply(&line)
+}
+
+/// Async version of `command` for outbound streams
+async fn command_async(
+    conn: &mut tokio::io::BufReader<tokio::net::TcpStream>,
+    cmd: &str,
+) -> Result<HashMap<String, String>, is2fp_error::Ip2pError> {
+    debug!("SAM: {}", redact(cmd));
+    let mut line = String::new();
+    let io_error = |e: std::io::Error| {
+        error!("SAM bridge connection failed: {}", e);
+        is2fp_error::Ip2pError::I2P
+    };
+    conn.get_mut().write_all(format!("{}\n", cmd).as_bytes()).await.map_err(io_error)?;
+    let read = tokio::time::timeout(Duration::from_secs(SAM_REPLY_TIMEOUT), conn.read_line(&mut line))
+        .await
+        .map_err(|_| {
+            error!("SAM bridge reply timed out");
+            is2fp_error::Ip2pError::I2P
+        })?
+        .map_err(io_error)?;
+    if read == 0 {
+        error!("SAM bridge closed the connection");
+        return Err(is2fp_error::Ip2pError::I2P);
+    }
+    parse_reply(&line)
+}
+
+/// Keep secret keys out of the logs
+fn redact(cmd: &str) -> String {
+    match cmd.find("DESTINATION=") {
+        Some(i) if cmd.starts_with("SESSION CREATE") => format!("{}DESTINATION=...", &cmd[..i]),
+        _ => String::from(cmd),
+    }
+}
+
+/// Parse `TOPIC TYPE KEY=VALUE ...` replies. Values may be quoted.
+///
+/// Every reply but `DEST REPLY` must carry a `RESULT`.
+fn parse_reply(line: &str) -> Result<HashMap<String, String>, is2fp_error::Ip2pError> {
+    let mut values: HashMap<String, String> = HashMap::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        let end = rest.find(' ').unwrap_or(rest.len());
+        let Some(eq) = rest[..end].find('=') else {
+            // topic and type words
+            rest = rest[end..].trim_start();
+            continue;
+        };
+        let key = String::from(&rest[..eq]);
+        let after = &rest[eq + 1..];
+        let (value, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let close = quoted.find('"').unwrap_or(quoted.len());
+                (&quoted[..close], quoted.get(close + 1..).unwrap_or(""))
+            }
+            None => {
+                let close = after.find(' ').unwrap_or(after.len());
+                (&after[..close], &after[close..])
+            }
+        };
+        values.insert(key, String::from(value));
+        rest = next.trim_start();
+    }
+    match values.get("RESULT").map(String::as_str) {
+        Some("OK") => Ok(values),
+        None if line.starts_with("DEST REPLY") => Ok(values),
+        None => {
+            error!("SAM bridge reply without a result: {}", line.trim());
+            Err(is2fp_error::Ip2pError::I2P)
+        }
+        Some(result) => {
+            error!(
+                "SAM bridge replied {}: {}",
+                result,
+                values.get("MESSAGE").map(String::as_str).unwrap_or_default()
+            );
+            Err(is2fp_error::Ip2pError::I2P)
+        }
+    }
+}
+
+/// Body of a successful HTTP response
+fn http_body(response: &[u8]) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .ok_or(is2fp_error::Ip2pError::I2P)?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        error!("relay responded with status {}", status);
+        return Err(is2fp_error::Ip2pError::Relay);
+    }
+    Ok(response[split + 4..].to_vec())
+}
+
+/// Decode base 64 in the I2P alphabet
+pub fn decode_i2p_base64(v: &str) -> Result<Vec<u8>, is2fp_error::Ip2pError> {
+    let mut result: Vec<u8> = Vec::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for c in v.trim_end_matches('=').bytes() {
+        let n = I2P_BASE64
+            .iter()
+            .position(|a| *a == c)
+            .ok_or(is2fp_error::Ip2pError::I2P)? as u32;
+        buffer = (buffer << 6) | n;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            result.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(result)
+}
+
+/// Encode with the I2P base 64 alphabet, padded
+pub fn encode_i2p_base64(v: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for b in v {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 6 {
+            bits -= 6;
+            result.push(I2P_BASE64[((buffer >> bits) & 63) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(I2P_BASE64[((buffer << (6 - bits)) & 63) as usize] as char);
+    }
+    while result.len() % 4 != 0 {
+        result.push('=');
+    }
+    result
+}
+
+/// Lowercase base 32 without padding
+fn encode_base32(v: &[u8]) -> String {
+    let mut result = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for b in v {
+        buffer = (buffer << 8) | *b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            result.push(BASE32[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        result.push(BASE32[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    result
+}
+
+/// Base 32 address of destination bytes
+pub(crate) fn b32_of(destination: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(destination);
+    format!("{}.b32.i2p", encode_base32(&hasher.finalize()[..]))
+}
+
+/// Base 32 address of a base 64 destination
+pub fn destination_b32(destination: &str) -> Result<String, is2fp_error::Ip2pError> {
+    Ok(b32_of(&decode_i2p_base64(destination)?))
+}
+
+/// Base 32 address of the destination a base 64 private key belongs to.
+///
+/// The key starts with the destination: public and signing keys, then a
+///
+/// certificate whose payload length follows its type byte.
+pub fn private_key_b32(sk: &str) -> Result<String, is2fp_error::Ip2pError> {
+    let key = decode_i2p_base64(sk)?;
+    let certificate = key.get(DESTINATION_KEYS_LENGTH..DESTINATION_KEYS_LENGTH + 3).ok_or_else(|| {
+        error!("private key is too short");
+        is2fp_error::Ip2pError::I2P
+    })?;
+    let length = DESTINATION_KEYS_LENGTH + 3 + u16::from_be_bytes([certificate[1], certificate[2]]) as usize;
+    let destination = key.get(..length).ok_or_else(|| {
+        error!("private key certificate is truncated");
+        is2fp_error::Ip2pError::I2P
+    })?;
+    Ok(b32_of(destination))
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::{
+        io::Read,
+        net::TcpListener,
+    };
+
+    /// Destination the mock bridge generates and resolves
+    const MOCK_PUB: &str = "Zm9vYmFy";
+
+    /// Minimal SAM bridge answering one command per line
+    fn mock_sam() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming().map_while(Result::ok) {
+                std::thread::spawn(move || {
+                    let mut conn = BufReader::new(stream);
+                    let mut line = String::new();
+                    while conn.read_line(&mut line).unwrap_or(0) > 0 {
+                        let reply = if line.starts_with("HELLO") {
+                            String::from("HELLO REPLY RESULT=OK VERSION=3.3")
+                        } else if line.starts_with("DEST GENERATE") {
+                            format!("DEST REPLY PUB={} PRIV={}AAAA", MOCK_PUB, MOCK_PUB)
+                        } else if line.starts_with("SESSION CREATE") {
+                            String::from("SESSION STATUS RESULT=OK DESTINATION=AAAA")
+                        } else if line.starts_with("NAMING LOOKUP") {
+                            format!("NAMING REPLY RESULT=OK NAME=test.b32.i2p VALUE={}", MOCK_PUB)
+                        } else if line.starts_with("STREAM") {
+                            String::from("STREAM STATUS RESULT=OK")
+                        } else {
+                            String::from("ERROR RESULT=I2P_ERROR MESSAGE=\"unknown command\"")
+                        };
+                        let _ = conn.get_mut().write_all(format!("{}\n", reply).as_bytes());
+                        if line.starts_with("STREAM CONNECT") {
+                            // echo the request body back as an http response
+                            let mut head = String::new();
+                            while conn.read_line(&mut head).unwrap_or(0) > 2 {
+                                head.clear();
+                            }
+                            let mut body = [0u8; 2];
+                            let _ = conn.read_exact(&mut body);
+                            let response = format!(
+                                "HTTP/1.0 200 OK\r\nContent-Length: 2\r\n\r\n{}",
+                                String::from_utf8_lossy(&body)
+                            );
+                            let _ = conn.get_mut().write_all(response.as_bytes());
+                            break;
+                        }
+                        line.clear();
+                    }
+                });
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn encoding_test() -> Result<(), is2fp_error::Ip2pError> {
+        assert_eq!(b"foobar".to_vec(), decode_i2p_base64("Zm9vYmFy")?);
+        assert_eq!(vec![0xfb], decode_i2p_base64("-w==")?);
+        assert!(decode_i2p_base64("+/").is_err());
+        assert_eq!("mzxw6ytboi", encode_base32(b"foobar"));
+        let b32 = destination_b32(MOCK_PUB)?;
+        assert_eq!(52 + ".b32.i2p".len(), b32.len());
+        assert_eq!("Zm9vYmFy", encode_i2p_base64(b"foobar"));
+        assert_eq!("-w==", encode_i2p_base64(&[0xfb]));
+        // destination with a 7 byte key certificate, followed by private keys
+        let mut destination = vec![1u8; DESTINATION_KEYS_LENGTH];
+        destination.extend_from_slice(&[5, 0, 4, 0, 7, 0, 4]);
+        let mut sk = destination.clone();
+        sk.extend_from_slice(&[2u8; 64]);
+        assert_eq!(b32_of(&destination), private_key_b32(&encode_i2p_base64(&sk))?);
+        assert!(private_key_b32(&encode_i2p_base64(&destination[..390])).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn check_destination_test() -> Result<(), is2fp_error::Ip2pError> {
+        let backend = SamBackend::new(DEFAULT_SAM_ADDRESS);
+        let mut destination = vec![1u8; DESTINATION_KEYS_LENGTH];
+        destination.extend_from_slice(&[0, 0, 0]);
+        let mut sk = destination.clone();
+        sk.extend_from_slice(&[2u8; 288]);
+        let dest = Destination { b32: b32_of(&destination), sk: encode_i2p_base64(&sk) };
+        backend.check_destination(&dest)?;
+        // another address, or a key in another encoding
+        assert!(backend.check_destination(&Destination { b32: destination_b32(MOCK_PUB)?, ..dest.clone() }).is_err());
+        assert!(backend.check_destination(&Destination { sk: hex::encode(&sk), ..dest }).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_reply_test() -> Result<(), is2fp_error::Ip2pError> {
+        let reply = parse_reply("HELLO REPLY RESULT=OK VERSION=3.3\n")?;
+        assert_eq!(Some("3.3"), reply.get("VERSION").map(String::as_str));
+        let reply = parse_reply("X Y RESULT=OK MESSAGE=\"with spaces\" A=B")?;
+        assert_eq!(Some("with spaces"), reply.get("MESSAGE").map(String::as_str));
+        assert_eq!(Some("B"), reply.get("A").map(String::as_str));
+        assert!(parse_reply("STREAM STATUS RESULT=CANT_REACH_PEER").is_err());
+        // only destination replies come without a result
+        assert!(parse_reply("DEST REPLY PUB=AAAA PRIV=AAAA").is_ok());
+        assert!(parse_reply("SESSION STATUS").is_err());
+        assert!(parse_reply("").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn closed_bridge_test() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            // hang up before replying
+            for stream in listener.incoming().map_while(Result::ok) {
+                drop(stream);
+            }
+        });
+        let backend = SamBackend::new(&address);
+        assert!(backend.create_destination().is_err());
+        assert!(!backend.is_running());
+    }
+
+    #[test]
+    fn mock_sam_test() -> Result<(), is2fp_error::Ip2pError> {
+        let backend = SamBackend::new(&mock_sam());
+        // outbound streams need a client session
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        assert!(runtime.block_on(backend.post_json(None, "test.b32.i2p", "/message", b"{}".to_vec())).is_err());
+        backend.start_router()?;
+        assert!(backend.is_running());
+        let dest = backend.create_destination()?;
+        assert_eq!(destination_b32(MOCK_PUB)?, dest.b32);
+        backend.start_server_tunnel(&dest, 5555)?;
+        let response = runtime.block_on(backend.post_json(None, "test.b32.i2p", "/message", b"{}".to_vec()))?;
+        assert_eq!(b"{}".to_vec(), response);
+        let client = backend.open_client()?;
+        let response = runtime.block_on(backend.post_json(Some(&client), "test.b32.i2p", "/message", b"{}".to_vec()))?;
+        assert_eq!(b"{}".to_vec(), response);
+        backend.close_client(&client)?;
+        backend.stop_server_tunnel(&dest)?;
+        assert_eq!(1, backend.sessions.lock().unwrap().len());
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+#![deny(missing_docs)]
+
+//! Graceful shutdown of the network loop, background tasks and router.
+//!
+//! Rocket stops accepting connections on SIGINT/SIGTERM and runs its
+//!
+//! shutdown fairings, which call `Shutdown::run`.
+
+use crate::{
+    backend::SharedBackend,
+    error as is2fp_error,
+    i2p,
+    storage::Storage,
+    utils,
+};
+use log::{
+    error,
+    info,
+    warn,
+};
+use std::{
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+use tokio::{
+    sync::watch,
+    task::JoinHandle,
+};
+
+/// Environment variable for the seconds allowed for shutdown
+pub const IS2FP_SHUTDOWN_TIMEOUT: &str = "IS2FP_SHUTDOWN_TIMEOUT";
+/// Default seconds allowed for shutdown
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 10;
+
+/// Shutdown coordinator shared between the server and background tasks
+pub type SharedShutdown = Arc<Shutdown>;
+
+/// Signals background tasks to stop and waits for them
+#[derive(Debug)]
+pub struct Shutdown {
+    tx: watch::Sender<bool>,
+    /// Tasks awaited before the router is stopped
+    tasks: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (tx, _) = watch::channel(false);
+        Shutdown { tx, tasks: Mutex::new(Vec::new()) }
+    }
+}
+
+impl Shutdown {
+    /// Receiver that changes to `true` once shutdown starts
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.tx.subscribe()
+    }
+    /// Whether shutdown has started
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+    /// Wait for `handle` during shutdown. Tracked tasks must return
+    ///
+    /// once their receiver changes.
+    pub fn track(&self, handle: JoinHandle<()>) {
+        match self.tasks.lock() {
+            Ok(mut tasks) => tasks.push(handle),
+            Err(_) => error!("failed to track task for shutdown"),
+        }
+    }
+    /// Stop background tasks, then tunnels and the router. Tasks still
+    ///
+    /// running after `IS2FP_SHUTDOWN_TIMEOUT` seconds are aborted, the
+    ///
+    /// router gets the same time to stop.
+    pub async fn run(&self, s: &dyn Storage, backend: SharedBackend) {
+        self.run_with(s, backend, get_timeout()).await
+    }
+    async fn run_with(&self, s: &dyn Storage, backend: SharedBackend, timeout: Duration) {
+        info!("shutting down");
+        self.tx.send_replace(true);
+        let tasks: Vec<JoinHandle<()>> = match self.tasks.lock() {
+            Ok(mut tasks) => tasks.drain(..).collect(),
+            Err(_) => Vec::new(),
+        };
+        let mut tasks = tasks;
+        let join = async {
+            for task in tasks.iter_mut() {
+                let _ = task.await;
+            }
+        };
+        if tokio::time::timeout(timeout, join).await.is_err() {
+            warn!("background tasks didn't stop after {}s, aborting them", timeout.as_secs());
+            for task in &tasks {
+                task.abort();
+            }
+        }
+        // tunnel and router calls block
+        let stop = async {
+            tokio::task::spawn_blocking(move || backend.stop())
+                .await
+                .unwrap_or(Err(is2fp_error::Ip2pError::I2P))
+        };
+        match tokio::time::timeout(timeout, stop).await {
+            Ok(Ok(_)) => info!("i2p stopped"),
+            Ok(Err(e)) => error!("failed to stop i2p: {:?}", e),
+            Err(_) => warn!("stopping i2p timed out after {}s", timeout.as_secs()),
+        }
+        i2p::set_status(s, i2p::Lifecycle::Stopped)
+            .unwrap_or_else(|_| error!("failed to write i2p status."));
+    }
+}
+
+/// Seconds allowed for shutdown from `IS2FP_SHUTDOWN_TIMEOUT`
+fn get_timeout() -> Duration {
+    Duration::from_secs(utils::get_env_parse(IS2FP_SHUTDOWN_TIMEOUT).unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT))
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{
+        backend::TestBackend,
+        sam::SamBackend,
+        storage::MemoryStorage,
+    };
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn shutdown_test() -> Result<(), is2fp_error::Ip2pError> {
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        let s = MemoryStorage::default();
+        i2p::reset_status(&s)?;
+        let shutdown = Shutdown::default();
+        let mut rx = shutdown.subscribe();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+        shutdown.track(runtime.spawn(async move {
+            let _ = rx.changed().await;
+            let _ = done_tx.send(());
+        }));
+        assert!(!shutdown.is_triggered());
+        // a backend that was never started stops immediately
+        runtime.block_on(shutdown.run(&s, Arc::new(SamBackend::new("127.0.0.1:0"))));
+        assert!(shutdown.is_triggered());
+        assert!(done_rx.try_recv().is_ok());
+        let status = runtime.block_on(i2p::check_connection(&s))?;
+        assert_eq!(i2p::Lifecycle::Stopped, status.state);
+        Ok(())
+    }
+
+    #[test]
+    fn stuck_task_test() -> Result<(), is2fp_error::Ip2pError> {
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        let s = MemoryStorage::default();
+        i2p::reset_status(&s)?;
+        let shutdown = Shutdown::default();
+        // ignores the shutdown signal, the sender is dropped on abort
+        let (aborted_tx, aborted_rx) = std::sync::mpsc::channel::<()>();
+        shutdown.track(runtime.spawn(async move {
+            let _aborted = aborted_tx;
+            std::future::pending::<()>().await
+        }));
+        let backend = Arc::new(TestBackend::default());
+        runtime.block_on(shutdown.run_with(&s, backend.clone(), Duration::from_millis(100)));
+        assert!(matches!(
+            aborted_rx.recv_timeout(Duration::from_secs(5)),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected)
+        ));
+        // the router is stopped even though the task timed out
+        assert!(backend.stopped.load(Ordering::SeqCst));
+        Ok(())
+    }
+}
@@ -0,0 +1,162 @@
+#![deny(missing_docs)]
+
+//! Outbound client destinations for stem messages.
+//!
+//! By default stem messages share one client destination, so a relay can
+//!
+//! link messages from the same originator. With `IS2FP_STEM_ISOLATION`
+//!
+//! each message, or each epoch of messages, gets a fresh destination
+//!
+//! that is torn down afterwards.
+
+use crate::{
+    backend::SharedBackend,
+    error as is2fp_error,
+    utils,
+};
+use log::{
+    error,
+    info,
+};
+use tokio::sync::Mutex;
+
+/// Environment variable for the isolation mode: `shared`, `message` or `epoch`
+pub const IS2FP_STEM_ISOLATION: &str = "IS2FP_STEM_ISOLATION";
+/// Environment variable for the seconds an epoch destination is used
+pub const IS2FP_STEM_EPOCH: &str = "IS2FP_STEM_EPOCH";
+/// Default epoch length in seconds
+const DEFAULT_STEM_EPOCH: u64 = 600;
+
+/// Which client destination stem messages are sent from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum StemIsolation {
+    /// The backend's shared client destination
+    #[default]
+    Shared,
+    /// A new destination for every message
+    Message,
+    /// A new destination every given number of seconds
+    Epoch(u64),
+}
+
+impl StemIsolation {
+    /// Mode from `IS2FP_STEM_ISOLATION` and `IS2FP_STEM_EPOCH`
+    pub fn from_env() -> Self {
+        let mode = std::env::var(IS2FP_STEM_ISOLATION).unwrap_or_default();
+        match mode.as_str() {
+            "message" => StemIsolation::Message,
+            "epoch" => StemIsolation::Epoch(
+                utils::get_env_parse::<u64>(IS2FP_STEM_EPOCH).filter(|v| *v > 0).unwrap_or(DEFAULT_STEM_EPOCH),
+            ),
+            "" | "shared" => StemIsolation::Shared,
+            _ => {
+                error!("unknown stem isolation {}, using shared", mode);
+                StemIsolation::Shared
+            }
+        }
+    }
+}
+
+/// Client destination of the current epoch and when it was opened
+#[derive(Debug)]
+struct EpochClient {
+    client: String,
+    opened: u64,
+}
+
+/// Hands out client destinations according to the isolation mode
+#[derive(Debug, Default)]
+pub struct StemClients {
+    isolation: StemIsolation,
+    epoch: Mutex<Option<EpochClient>>,
+}
+
+impl StemClients {
+    /// Clients for the given isolation mode
+    pub fn new(isolation: StemIsolation) -> Self {
+        info!("stem isolation: {:?}", isolation);
+        StemClients { isolation, epoch: Mutex::new(None) }
+    }
+    /// Client to send the next message from, `None` for the shared one
+    pub async fn acquire(&self, backend: &SharedBackend) -> Result<Option<String>, is2fp_error::Ip2pError> {
+        match self.isolation {
+            StemIsolation::Shared => Ok(None),
+            StemIsolation::Message => Ok(Some(open(backend).await?)),
+            StemIsolation::Epoch(length) => {
+                let now = utils::get_unix_time()?;
+                let mut epoch = self.epoch.lock().await;
+                if let Some(current) = epoch.as_ref() {
+                    if now.saturating_sub(current.opened) < length {
+                        return Ok(Some(current.client.clone()));
+                    }
+                }
+                if let Some(old) = epoch.take() {
+                    close(backend, old.client).await;
+                }
+                let client = open(backend).await?;
+                *epoch = Some(EpochClient { client: client.clone(), opened: now });
+                Ok(Some(client))
+            }
+        }
+    }
+    /// Return a client after sending. Per message clients are torn down.
+    pub async fn release(&self, backend: &SharedBackend, client: Option<String>) {
+        if let (StemIsolation::Message, Some(client)) = (self.isolation, client) {
+            close(backend, client).await;
+        }
+    }
+}
+
+/// Open a client destination, the backend blocks until its tunnels are built
+async fn open(backend: &SharedBackend) -> Result<String, is2fp_error::Ip2pError> {
+    let backend = backend.clone();
+    tokio::task::spawn_blocking(move || backend.open_client())
+        .await
+        .map_err(|_| is2fp_error::Ip2pError::I2P)?
+}
+
+async fn close(backend: &SharedBackend, client: String) {
+    let backend = backend.clone();
+    let result = tokio::task::spawn_blocking(move || backend.close_client(&client)).await;
+    if !matches!(result, Ok(Ok(_))) {
+        error!("failed to close stem client destination");
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::backend::TestBackend;
+    use std::sync::Arc;
+
+    #[test]
+    fn stem_clients_test() -> Result<(), is2fp_error::Ip2pError> {
+        let runtime = tokio::runtime::Runtime::new().map_err(|_| is2fp_error::Ip2pError::Unknown)?;
+        let test_backend = Arc::new(TestBackend::default());
+        let backend: SharedBackend = test_backend.clone();
+        let open_clients = || test_backend.clients.lock().map(|c| c.len()).unwrap_or_default();
+        runtime.block_on(async {
+            let shared = StemClients::new(StemIsolation::Shared);
+            assert_eq!(None, shared.acquire(&backend).await?);
+            // a new destination per message, torn down after sending
+            let message = StemClients::new(StemIsolation::Message);
+            let first = message.acquire(&backend).await?;
+            let second = message.acquire(&backend).await?;
+            assert!(first.is_some() && first != second);
+            message.release(&backend, first).await;
+            message.release(&backend, second).await;
+            assert_eq!(0, open_clients());
+            // one destination for the whole epoch
+            let epoch = StemClients::new(StemIsolation::Epoch(3600));
+            let first = epoch.acquire(&backend).await?;
+            epoch.release(&backend, first.clone()).await;
+            assert_eq!(first, epoch.acquire(&backend).await?);
+            assert_eq!(1, open_clients());
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,117 @@
+#![deny(missing_docs)]
+
+//! Storage backends for the key-value layer.
+//!
+//! `DatabaseEnvironment` persists to LMDB. `MemoryStorage` keeps values
+//!
+//! in memory so tests can run isolated and in parallel.
+
+use crate::db;
+use kn0sys_lmdb_rs::MdbError;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+};
+
+/// Storage shared between the relay server, network loop and background tasks
+pub type SharedStorage = Arc<dyn Storage>;
+
+/// Logical key-value operations. Values are written and replaced
+///
+/// atomically and missing keys read as empty vectors.
+pub trait Storage: Send + Sync {
+    /// Read a value, empty if the key doesn't exist
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError>;
+    /// Write a value, replacing any existing value
+    fn write(&self, k: &[u8], v: &[u8]) -> Result<(), MdbError>;
+    /// Write several values at once, either all of them or none
+    fn write_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), MdbError>;
+    /// Delete a value
+    fn delete(&self, k: &[u8]) -> Result<(), MdbError>;
+    /// Every key starting with `prefix` and its value, in ascending order
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, MdbError>;
+    /// Every key starting with `prefix` in ascending order, values aren't read
+    fn scan_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, MdbError>;
+}
+
+impl Storage for db::DatabaseEnvironment {
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+        db::DatabaseEnvironment::read(self, k)
+    }
+    fn write(&self, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+        db::write_chunks(self, k, v)
+    }
+    fn write_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), MdbError> {
+        db::write_batch(self, entries)
+    }
+    fn delete(&self, k: &[u8]) -> Result<(), MdbError> {
+        db::DatabaseEnvironment::delete(&self.env, &self.handle, k)
+    }
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, MdbError> {
+        db::DatabaseEnvironment::scan_prefix(self, prefix)
+    }
+    fn scan_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, MdbError> {
+        db::DatabaseEnvironment::scan_keys(self, prefix)
+    }
+}
+
+/// In-memory storage backend
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    values: Mutex<BTreeMap<Vec<u8>, Vec<u8>>>,
+}
+
+impl MemoryStorage {
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<Vec<u8>, Vec<u8>>>, MdbError> {
+        self.values.lock().map_err(|_| MdbError::Panic)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn read(&self, k: &[u8]) -> Result<Vec<u8>, MdbError> {
+        if k.is_empty() {
+            return Err(MdbError::NotFound);
+        }
+        Ok(self.lock()?.get(k).cloned().unwrap_or_default())
+    }
+    fn write(&self, k: &[u8], v: &[u8]) -> Result<(), MdbError> {
+        if k.is_empty() {
+            return Err(MdbError::NotFound);
+        }
+        self.lock()?.insert(k.to_vec(), v.to_vec());
+        Ok(())
+    }
+    fn write_batch(&self, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), MdbError> {
+        if entries.iter().any(|(k, _)| k.is_empty()) {
+            return Err(MdbError::NotFound);
+        }
+        self.lock()?.extend(entries.iter().cloned());
+        Ok(())
+    }
+    fn delete(&self, k: &[u8]) -> Result<(), MdbError> {
+        if k.is_empty() {
+            return Err(MdbError::NotFound);
+        }
+        self.lock()?.remove(k);
+        Ok(())
+    }
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, MdbError> {
+        let values = self.lock()?;
+        Ok(values
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect())
+    }
+    fn scan_keys(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, MdbError> {
+        let values = self.lock()?;
+        Ok(values
+            .range(prefix.to_vec()..)
+            .take_while(|(k, _)| k.starts_with(prefix))
+            .map(|(k, _)| k.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,186 @@
+#![deny(missing_docs)]
+
+//! Typed, namespaced key-value layer on top of `db::DatabaseEnvironment`.
+
+use crate::{
+    error as is2fp_error,
+    storage::Storage,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+
+/// Logical groupings of keys in the database
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Namespace {
+    /// Base 32 relay addresses learned from peers, keyed by peer id
+    Relay,
+    /// Messages addressed to the local peer
+    Inbox,
+    /// Messages waiting for fluff propagation
+    Fluff,
+    /// Node settings and i2p state (keys are stored as-is)
+    Settings,
+}
+
+impl Namespace {
+    /// Key prefix used for this namespace
+    pub fn prefix(&self) -> &'static str {
+        match *self {
+            Namespace::Relay => "b32",
+            Namespace::Inbox => "inbox",
+            Namespace::Fluff => "fluff",
+            Namespace::Settings => "",
+        }
+    }
+    /// Build the raw database key for `key` in this namespace.
+    ///
+    /// An empty `key` refers to the namespace itself (e.g. "inbox").
+    pub fn key(&self, key: &str) -> Vec<u8> {
+        let prefix = self.prefix();
+        if prefix.is_empty() {
+            key.as_bytes().to_vec()
+        } else if key.is_empty() {
+            prefix.as_bytes().to_vec()
+        } else {
+            format!("{}-{}", prefix, key).as_bytes().to_vec()
+        }
+    }
+    /// Raw key prefix shared by every keyed entry in this namespace
+    pub fn scan_prefix(&self) -> Vec<u8> {
+        let prefix = self.prefix();
+        if prefix.is_empty() {
+            Vec::new()
+        } else {
+            format!("{}-", prefix).as_bytes().to_vec()
+        }
+    }
+}
+
+/// Typed access to a storage backend
+pub struct Store;
+
+impl Store {
+    /// Read and decode a value. Missing keys return `None`.
+    pub fn get<T: DeserializeOwned>(
+        s: &dyn Storage,
+        ns: Namespace,
+        key: &str,
+    ) -> Result<Option<T>, is2fp_error::Ip2pError> {
+        let bytes = s.read(&ns.key(key)).map_err(is2fp_error::Ip2pError::Database)?;
+        if bytes.is_empty() {
+            return Ok(None);
+        }
+        let value: T = bincode::deserialize(&bytes[..]).map_err(|e| {
+            log::error!("failed to decode {:?} key {}: {:?}", ns, key, e);
+            is2fp_error::Ip2pError::Decode(e)
+        })?;
+        Ok(Some(value))
+    }
+    /// Encode and write a value, replacing any existing value
+    pub fn put<T: Serialize>(
+        s: &dyn Storage,
+        ns: Namespace,
+        key: &str,
+        value: &T,
+    ) -> Result<(), is2fp_error::Ip2pError> {
+        let bytes = bincode::serialize(value).map_err(is2fp_error::Ip2pError::Encode)?;
+        s.write(&ns.key(key), &bytes).map_err(is2fp_error::Ip2pError::Database)
+    }
+    /// Encode a value as a key and value for `put_all`
+    pub fn entry<T: Serialize>(
+        ns: Namespace,
+        key: &str,
+        value: &T,
+    ) -> Result<(Vec<u8>, Vec<u8>), is2fp_error::Ip2pError> {
+        let bytes = bincode::serialize(value).map_err(is2fp_error::Ip2pError::Encode)?;
+        Ok((ns.key(key), bytes))
+    }
+    /// Write entries from `entry` at once, either all of them or none
+    pub fn put_all(s: &dyn Storage, entries: &[(Vec<u8>, Vec<u8>)]) -> Result<(), is2fp_error::Ip2pError> {
+        s.write_batch(entries).map_err(is2fp_error::Ip2pError::Database)
+    }
+    /// Read and decode every keyed entry in a namespace. Keys are
+    ///
+    /// returned without the namespace prefix.
+    pub fn scan<T: DeserializeOwned>(
+        s: &dyn Storage,
+        ns: Namespace,
+    ) -> Result<Vec<(String, T)>, is2fp_error::Ip2pError> {
+        let prefix = ns.scan_prefix();
+        let entries = s.scan_prefix(&prefix).map_err(is2fp_error::Ip2pError::Database)?;
+        let mut result: Vec<(String, T)> = Vec::new();
+        for (k, v) in entries {
+            let key = String::from_utf8_lossy(&k[prefix.len()..]).to_string();
+            if v.is_empty() {
+                continue;
+            }
+            let value: T = bincode::deserialize(&v[..]).map_err(|e| {
+                log::error!("failed to decode {:?} key {}: {:?}", ns, key, e);
+                is2fp_error::Ip2pError::Decode(e)
+            })?;
+            result.push((key, value));
+        }
+        Ok(result)
+    }
+    /// Keys of every entry in a namespace without the namespace prefix,
+    ///
+    /// in ascending order. Values aren't read or decoded.
+    pub fn keys(s: &dyn Storage, ns: Namespace) -> Result<Vec<String>, is2fp_error::Ip2pError> {
+        let prefix = ns.scan_prefix();
+        let keys = s.scan_keys(&prefix).map_err(is2fp_error::Ip2pError::Database)?;
+        Ok(keys
+            .iter()
+            .map(|k| String::from_utf8_lossy(&k[prefix.len()..]).to_string())
+            .collect())
+    }
+    /// Delete a value
+    pub fn delete(s: &dyn Storage, ns: Namespace, key: &str) -> Result<(), is2fp_error::Ip2pError> {
+        s.delete(&ns.key(key)).map_err(is2fp_error::Ip2pError::Database)
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn store_test() -> Result<(), is2fp_error::Ip2pError> {
+        let s = MemoryStorage::default();
+        Store::put(&s, Namespace::Relay, "peer-a", &String::from("a.b32.i2p"))?;
+        Store::put(&s, Namespace::Relay, "peer-b", &String::from("b.b32.i2p"))?;
+        Store::put(&s, Namespace::Settings, "app-b32", &String::from("app.b32.i2p"))?;
+        let actual: Option<String> = Store::get(&s, Namespace::Relay, "peer-a")?;
+        assert_eq!(Some(String::from("a.b32.i2p")), actual);
+        let missing: Option<String> = Store::get(&s, Namespace::Relay, "peer-c")?;
+        assert!(missing.is_none());
+        let relays: Vec<(String, String)> = Store::scan(&s, Namespace::Relay)?;
+        assert_eq!(vec!["peer-a", "peer-b"], relays.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>());
+        assert_eq!(vec!["peer-a", "peer-b"], Store::keys(&s, Namespace::Relay)?);
+        Store::delete(&s, Namespace::Relay, "peer-a")?;
+        let deleted: Option<String> = Store::get(&s, Namespace::Relay, "peer-a")?;
+        assert!(deleted.is_none());
+        let entries = vec![
+            Store::entry(Namespace::Settings, "app-b32", &String::from("new.b32.i2p"))?,
+            Store::entry(Namespace::Settings, "app-created", &1u64)?,
+        ];
+        Store::put_all(&s, &entries)?;
+        let b32: Option<String> = Store::get(&s, Namespace::Settings, "app-b32")?;
+        assert_eq!(Some(String::from("new.b32.i2p")), b32);
+        assert_eq!(Some(1u64), Store::get(&s, Namespace::Settings, "app-created")?);
+        Ok(())
+    }
+
+    #[test]
+    fn decode_error_test() {
+        let s = MemoryStorage::default();
+        s.write(&Namespace::Fluff.key(""), &[0xff]).unwrap_or_default();
+        let actual: Result<Option<Vec<String>>, _> = Store::get(&s, Namespace::Fluff, "");
+        assert!(matches!(actual, Err(is2fp_error::Ip2pError::Decode(_))));
+    }
+}
@@ -0,0 +1,176 @@
+#![deny(missing_docs)]
+
+//! Tunnel length, quantity, variance and backup count.
+//!
+//! The relay server and the outbound client tunnels are configured
+//!
+//! separately. Values are checked against ranges that keep tunnels
+//!
+//! anonymous and buildable.
+
+use crate::error as is2fp_error;
+use log::{
+    error,
+    warn,
+};
+use std::ops::RangeInclusive;
+
+/// Environment variable for the relay server tunnel options, e.g.
+///
+/// `length=3,quantity=3,variance=0,backup=1`
+pub const IS2FP_SERVER_TUNNEL: &str = "IS2FP_SERVER_TUNNEL";
+/// Environment variable for the http proxy and stem client tunnel options
+pub const IS2FP_CLIENT_TUNNEL: &str = "IS2FP_CLIENT_TUNNEL";
+/// Hops per tunnel, zero hop tunnels expose the node
+const LENGTH_RANGE: RangeInclusive<i64> = 1..=7;
+/// Tunnels per direction
+const QUANTITY_RANGE: RangeInclusive<i64> = 1..=16;
+/// Random hops added to `length`, or added or removed if negative
+const VARIANCE_RANGE: RangeInclusive<i64> = -2..=2;
+/// Standby tunnels per direction
+const BACKUP_RANGE: RangeInclusive<i64> = 0..=3;
+
+/// Options for the inbound and outbound tunnels of one destination
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TunnelOptions {
+    /// Hops per tunnel
+    pub length: u8,
+    /// Tunnels per direction
+    pub quantity: u8,
+    /// Random hops added to or removed from `length`
+    pub variance: i8,
+    /// Standby tunnels per direction
+    pub backup: u8,
+}
+
+impl TunnelOptions {
+    /// Router defaults, with a standby tunnel so the relay stays reachable
+    pub const SERVER: TunnelOptions = TunnelOptions { length: 3, quantity: 2, variance: 0, backup: 1 };
+    /// Router defaults
+    pub const CLIENT: TunnelOptions = TunnelOptions { length: 3, quantity: 2, variance: 0, backup: 0 };
+
+    /// Override `defaults` with a comma separated `key=value` list
+    pub fn parse(options: &str, defaults: TunnelOptions) -> Result<Self, is2fp_error::Ip2pError> {
+        let mut result = defaults;
+        for option in options.split(',').map(str::trim).filter(|o| !o.is_empty()) {
+            let (key, value) = option.split_once('=').ok_or_else(|| {
+                error!("malformed tunnel option: {}", option);
+                is2fp_error::Ip2pError::I2P
+            })?;
+            let (key, value) = (key.trim(), value.trim());
+            match key {
+                "length" => result.length = parse_value(key, value, LENGTH_RANGE)? as u8,
+                "quantity" => result.quantity = parse_value(key, value, QUANTITY_RANGE)? as u8,
+                "variance" => result.variance = parse_value(key, value, VARIANCE_RANGE)? as i8,
+                "backup" => result.backup = parse_value(key, value, BACKUP_RANGE)? as u8,
+                _ => {
+                    error!("unknown tunnel option: {}", key);
+                    return Err(is2fp_error::Ip2pError::I2P);
+                }
+            }
+        }
+        // a negative variance randomizes in both directions
+        let variance = (result.variance as i64).abs();
+        let (shortest, longest) = (result.length as i64 - variance, result.length as i64 + variance);
+        if !LENGTH_RANGE.contains(&shortest) || !LENGTH_RANGE.contains(&longest) {
+            error!(
+                "tunnel length {} with variance {} allows {} to {} hops, must be between {} and {}",
+                result.length,
+                result.variance,
+                shortest,
+                longest,
+                LENGTH_RANGE.start(),
+                LENGTH_RANGE.end()
+            );
+            return Err(is2fp_error::Ip2pError::I2P);
+        }
+        if shortest < 2 {
+            warn!("tunnels with {} hop offer little anonymity", shortest);
+        }
+        Ok(result)
+    }
+    /// I2CP session options, space separated
+    pub fn to_i2cp(&self) -> String {
+        ["inbound", "outbound"]
+            .iter()
+            .map(|d| {
+                format!(
+                    "{d}.length={} {d}.quantity={} {d}.lengthVariance={} {d}.backupQuantity={}",
+                    self.length, self.quantity, self.variance, self.backup
+                )
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+}
+
+/// Parse a value and check it is within `range`
+fn parse_value(key: &str, value: &str, range: RangeInclusive<i64>) -> Result<i64, is2fp_error::Ip2pError> {
+    match value.parse::<i64>() {
+        Ok(v) if range.contains(&v) => Ok(v),
+        _ => {
+            error!("tunnel {} must be between {} and {}, got {}", key, range.start(), range.end(), value);
+            Err(is2fp_error::Ip2pError::I2P)
+        }
+    }
+}
+
+/// Options of the relay server and client tunnels
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TunnelConfig {
+    /// Relay server tunnels
+    pub server: TunnelOptions,
+    /// Http proxy and stem client tunnels
+    pub client: TunnelOptions,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig { server: TunnelOptions::SERVER, client: TunnelOptions::CLIENT }
+    }
+}
+
+impl TunnelConfig {
+    /// Options from `IS2FP_SERVER_TUNNEL` and `IS2FP_CLIENT_TUNNEL`
+    pub fn from_env() -> Result<Self, is2fp_error::Ip2pError> {
+        let read = |name: &str| std::env::var(name).unwrap_or_default();
+        Ok(TunnelConfig {
+            server: TunnelOptions::parse(&read(IS2FP_SERVER_TUNNEL), TunnelOptions::SERVER)?,
+            client: TunnelOptions::parse(&read(IS2FP_CLIENT_TUNNEL), TunnelOptions::CLIENT)?,
+        })
+    }
+}
+
+// Tests
+//-------------------------------------------------------------------------------
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_test() -> Result<(), is2fp_error::Ip2pError> {
+        assert_eq!(TunnelOptions::SERVER, TunnelOptions::parse("", TunnelOptions::SERVER)?);
+        let options = TunnelOptions::parse("length=4, quantity=3,variance=-1,backup=2", TunnelOptions::CLIENT)?;
+        assert_eq!(TunnelOptions { length: 4, quantity: 3, variance: -1, backup: 2 }, options);
+        for invalid in ["length=0", "length=8", "quantity=0", "variance=3", "backup=4", "hops=3", "length", "length=x"] {
+            assert!(TunnelOptions::parse(invalid, TunnelOptions::CLIENT).is_err(), "{}", invalid);
+        }
+        // variance must not allow zero hop tunnels
+        assert!(TunnelOptions::parse("length=1,variance=-1", TunnelOptions::CLIENT).is_err());
+        assert!(TunnelOptions::parse("length=2,variance=2", TunnelOptions::CLIENT).is_err());
+        assert!(TunnelOptions::parse("length=3,variance=-2", TunnelOptions::CLIENT).is_ok());
+        // nor more hops than the maximum length
+        assert!(TunnelOptions::parse("length=7,variance=2", TunnelOptions::CLIENT).is_err());
+        assert!(TunnelOptions::parse("length=6,variance=-2", TunnelOptions::CLIENT).is_err());
+        assert!(TunnelOptions::parse("length=5,variance=2", TunnelOptions::CLIENT).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn i2cp_test() {
+        let i2cp = TunnelOptions::SERVER.to_i2cp();
+        assert!(i2cp.starts_with("inbound.length=3 inbound.quantity=2 "));
+        assert!(i2cp.ends_with("outbound.lengthVariance=0 outbound.backupQuantity=1"));
+    }
+}
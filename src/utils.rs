@@ -1,7 +1,31 @@
-use kn0sys_lmdb_rs::MdbError;
-use crate::{i2p, db, error as is2fp_error};
+use crate::{
+    backend::SharedBackend,
+    db,
+    health,
+    i2p,
+    inbox,
+    retention,
+    rotation::{
+        self,
+        SharedRotation,
+    },
+    error as is2fp_error,
+    shutdown::SharedShutdown,
+    stem::{
+        self,
+        StemClients,
+    },
+    storage::{
+        SharedStorage,
+        Storage,
+    },
+    store::{
+        Namespace,
+        Store,
+    },
+};
 use log::*;
-use tokio::{io, select, io::AsyncBufReadExt};
+use tokio::{io, select, io::AsyncBufReadExt, sync::watch};
 use std::{
     time::Duration,
 };
@@ -19,16 +43,31 @@ use sha2::{
 };
 use rocket::serde::json::Json;
 use lazy_static::lazy_static;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
 
-const RELAY_KEY: &str = "b32";
-const FLUFF_KEY: &str = "fluff";
 const NETWORK_FLUFF: u64 = 32;
 const POW_LIMIT: u64 = 1618;
+/// Seconds between refreshing the relays of connected peers
+const RELAY_REFRESH_INTERVAL: u64 = 3600;
 
 lazy_static! {
     /// used to prevent LMDB errors while propagating fluff messages
-    static ref IS_FLUFF_LOCKED: Mutex<bool> = Mutex::new(false);
+    static ref FLUFF_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Hold the fluff queue until the guard is dropped
+fn lock_fluff() -> MutexGuard<'static, ()> {
+    // the lock guards no data, a panic while holding it leaves nothing behind
+    FLUFF_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Hold the fluff queue if nothing else does
+fn try_lock_fluff() -> Option<MutexGuard<'static, ()>> {
+    match FLUFF_LOCK.try_lock() {
+        Ok(guard) => Some(guard),
+        Err(TryLockError::Poisoned(e)) => Some(e.into_inner()),
+        Err(TryLockError::WouldBlock) => None,
+    }
 }
 
 
@@ -91,91 +130,133 @@ pub struct Message {
     pub pow_solution: String,
 }
 
+/// A relay learned via B32Exchange
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct Relay {
+    pub b32: String,
+    /// Unix time of the last B32Exchange from this relay
+    pub seen: u64,
+}
+
+/// Seconds since the unix epoch
+pub fn get_unix_time() -> Result<u64, is2fp_error::Ip2pError> {
+    use::std::time::{SystemTime, UNIX_EPOCH };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| is2fp_error::Ip2pError::Unknown)?
+        .as_secs();
+    Ok(now)
+}
+
+/// Parse the environment variable `name`, `None` if it is unset or invalid
+pub fn get_env_parse<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().and_then(|v| v.parse::<T>().ok())
+}
+
 /// app port
 pub fn get_app_port() -> u16 {
     // attempt environment variable extraction, fall to default
-    let port = std::env::var(i2p::IS2FP_PORT)
-        .unwrap_or(i2p::DEFAULT_APP_PORT.to_string());
-    if port.is_empty() {
-        i2p::DEFAULT_APP_PORT
-    } else {
-        port.parse::<u16>().unwrap_or(i2p::DEFAULT_APP_PORT)
-    }
+    get_env_parse::<u16>(i2p::IS2FP_PORT).unwrap_or(i2p::DEFAULT_APP_PORT)
 }
 
-/// i2p http proxy
-pub fn get_i2p_http_proxy() -> String {
-    // attempt environment variable extraction, fall to default
-    let proxy = std::env::var(i2p::I2P_PROXY_HOST);
-    if let Err(e) = &proxy {
-        log::warn!("failed to read i2p proxy host: {:?}", e);
-    }
-    let u_proxy = proxy.unwrap_or_default();
-    if u_proxy.is_empty() {
-        format!("{}:{}", i2p::DEFAULT_HTTP_PROXY_HOST, i2p::DEFAULT_HTTP_PROXY_PORT)
-    } else {
-        u_proxy
+/// Command line flags and the environment variables they set
+const CLI_FLAGS: [(&str, &str); 1] = [("--data-dir", db::IS2FP_DATA_DIR)];
+
+/// Apply command line flags. Each flag overrides its environment variable
+///
+/// so it must be called before the database is first accessed.
+///
+/// Setting variables isn't thread safe, call it before any thread starts.
+pub fn apply_args() {
+    let args: Vec<String> = std::env::args().collect();
+    for (flag, var) in CLI_FLAGS {
+        if let Some(pos) = args.iter().position(|a| a == flag) {
+            match args.get(pos + 1) {
+                Some(value) => std::env::set_var(var, value),
+                None => log::error!("missing value for {}", flag),
+            }
+        }
     }
 }
 
-fn reset_i2p_status() -> Result<(), is2fp_error::Ip2pError> {
-    let l = &db::DATABASE_LOCK;
-    let v = bincode::serialize(&i2p::ProxyStatus::Opening).unwrap_or_default();
-    db::write_chunks(&l.env, &l.handle, i2p::I2P_STATUS.as_bytes(), &v)
-        .map_err(|_| is2fp_error::Ip2pError::Database(MdbError::Panic))?;
-    Ok(())
+/// Positional command line arguments, without flags and their values
+pub fn get_command_args() -> Vec<String> {
+    let mut args: Vec<String> = Vec::new();
+    let mut skip_value = false;
+    for arg in std::env::args().skip(1) {
+        if skip_value {
+            skip_value = false;
+        } else if CLI_FLAGS.iter().any(|(flag, _)| *flag == arg) {
+            skip_value = true;
+        } else {
+            args.push(arg);
+        }
+    }
+    args
 }
 
-fn handle_messages(msg: Message, peer_id: libp2p::PeerId, local_peer_id: libp2p::PeerId) -> Result<(), is2fp_error::Ip2pError> {
+fn handle_messages(s: &dyn Storage, mut msg: Message, peer_id: libp2p::PeerId, local_peer_id: libp2p::PeerId) -> Result<(), is2fp_error::Ip2pError> {
     log::info!("handling message type: {:?}", &msg.m_type);
     if msg.m_type == MessageType::B32Exchange {
         log::info!("processing address {} for relays", &msg.data.clone());
         // save b32.i2p for stem selection
         let mid = &msg.mid.clone();
-        let l = &db::DATABASE_LOCK;
-        let key = format!("{}-{}", RELAY_KEY, &peer_id);
-        let b_key = key.as_bytes().to_vec();
-        let b32 = db::DatabaseEnvironment::read(&l.env, &l.handle, &b_key)
-            .unwrap_or_default();
-        if b32.is_empty() {
+        let key = format!("{}", &peer_id);
+        let relay: Option<Relay> = Store::get(s, Namespace::Relay, &key)?;
+        let new_relay = Relay { b32: String::from(&msg.data), seen: get_unix_time()? };
+        if relay.is_none() {
             log::info!("writing new relay:{:?} to lmdb", &peer_id);
-            let bytes_b32 = bincode::serialize(&msg.data).unwrap_or_default();
-            db::write_chunks(&l.env, &l.handle, &b_key, &bytes_b32)
+            Store::put(s, Namespace::Relay, &key, &new_relay)
                 .unwrap_or_else(|_| log::error!("failed to add b32: {} for peer {}", &msg.data, &peer_id));
         } else {
+            if relay.is_some_and(|r| r.b32 != new_relay.b32) {
+                log::info!("relay {:?} rotated its address", &peer_id);
+            }
+            // keep known relays from expiring
+            Store::put(s, Namespace::Relay, &key, &new_relay)
+                .unwrap_or_else(|_| log::error!("failed to refresh relay {}", &peer_id));
             // TODO: environment variable for saving all messages
             // for now, just save messages directed to our peer_id
             let is_valid = MessageLimits::validate(&msg);
-            let inbox_key = "inbox".as_bytes().to_vec();
-            let mut our_messages: Vec<Message> = Vec::new();
-            let b_old_messages = db::DatabaseEnvironment::read(&l.env, &l.handle, &inbox_key)
-                .map_err(|_| is2fp_error::Ip2pError::Database(MdbError::Panic))?;
-            let mut old_messages: Vec<Message> = bincode::deserialize(&b_old_messages[..]).unwrap_or_default();
-            our_messages.append(&mut old_messages);
             if is_valid && &msg.to == &format!("{local_peer_id}") {
                 log::debug!("saving new message to db");
-                our_messages.push(msg);
+                if msg.created == 0 {
+                    msg.created = get_unix_time()?;
+                }
+                if let Err(e) = inbox::save(s, &msg) {
+                    log::error!("failed to add message {} to db: {:?}", mid, e);
+                }
             }
-            let b_our_messages = bincode::serialize(&our_messages).unwrap_or_default();
-            db::write_chunks(&l.env, &l.handle, &inbox_key, &b_our_messages)
-                .unwrap_or_else(|_| log::error!("failed to add message {} to db", mid));
         }
     }
     Ok(())
 }
 
-pub async fn select_invisible_stem(mut msg: Message, peers: Vec<&libp2p::PeerId>) -> Result<(), is2fp_error::Ip2pError> {
+pub async fn select_invisible_stem(
+    s: &dyn Storage,
+    backend: &SharedBackend,
+    stems: &StemClients,
+    mut msg: Message,
+    peers: Vec<&libp2p::PeerId>,
+) -> Result<(), is2fp_error::Ip2pError> {
     log::info!("start invisible stem selection");
     log::info!("connected peers: {}", peers.len());
-    // get random peer and their b32 address
-    let r_peer = *peers.choose(&mut rand::rng()).unwrap();
+    // get random peer and their b32 address, preferring connected peers
+    let relays: Vec<(String, Relay)> = Store::scan(s, Namespace::Relay)?;
+    log::info!("known relays: {}", relays.len());
+    let connected: Vec<&(String, Relay)> = relays
+        .iter()
+        .filter(|(peer, _)| peers.iter().any(|p| &format!("{}", p) == peer))
+        .collect();
+    let r_relay = if connected.is_empty() {
+        relays.iter().choose(&mut rand::rng())
+    } else {
+        connected.into_iter().choose(&mut rand::rng())
+    };
+    let (r_peer, relay) = r_relay.ok_or(is2fp_error::Ip2pError::Relay)?;
     log::debug!("random relay: {:?}", r_peer);
-    let l = &db::DATABASE_LOCK;
-    let b32_key = format!("{}-{}", RELAY_KEY, r_peer);
-    let bytes_b32_key = b32_key.as_bytes().to_vec();
-    let b_b32 = db::DatabaseEnvironment::read(&l.env, &l.handle, &bytes_b32_key)
-        .unwrap_or_default();
-    let relay_b32: String = bincode::deserialize(&b_b32[..]).unwrap_or_default();
+    let relay_b32 = String::from(&relay.b32);
     // generate pow problem
     let big_r: u64 = rand::random_range(0..POW_LIMIT);
     let mut hasher = Sha512::new();
@@ -188,21 +269,17 @@ pub async fn select_invisible_stem(mut msg: Message, peers: Vec<&libp2p::PeerId>
     hasher.update(&msg.data.as_bytes());
     let hash = hasher.finalize().to_owned();
     msg.mid = hex::encode(&hash[..]);
-    // pass message to invisible stem via i2p http proxy
+    // pass message to invisible stem over i2p
     info!("broadcasting message to relay: {}", &relay_b32);
-    let host = get_i2p_http_proxy();
-    log::debug!("setting i2p proxy to: {}", &host);
-    let proxy = reqwest::Proxy::http(&host)
-        .map_err(|_| is2fp_error::Ip2pError::Relay)?;
-    let client = reqwest::Client::builder().proxy(proxy).build();
-    match client.map_err(|_| is2fp_error::Ip2pError::Relay)?
-        .post(format!("http://{}/message", &relay_b32))
-        .json(&msg)
-        .send()
-        .await
-    {
+    let body = rocket::serde::json::to_string(&msg)
+        .map_err(|_| is2fp_error::Ip2pError::Message)?
+        .into_bytes();
+    let client = stems.acquire(backend).await?;
+    let response = backend.post_json(client.as_deref(), &relay_b32, "/message", body).await;
+    stems.release(backend, client).await;
+    match response {
         Ok(response) => {
-            let res = response.json::<Message>().await;
+            let res = rocket::serde::json::from_slice::<Message>(&response);
             match res {
                 Ok(_) => log::info!("relay success"),
                 _ => log::warn!("unknown relay status"),
@@ -215,58 +292,63 @@ pub async fn select_invisible_stem(mut msg: Message, peers: Vec<&libp2p::PeerId>
     Ok(())
 }
 
-fn extract_fluff() -> Vec<Message> {
-    let l = &db::DATABASE_LOCK;
-    let k = FLUFF_KEY.as_bytes().to_vec();
-    let b_fluff = db::DatabaseEnvironment::read(&l.env, &l.handle, &k).unwrap_or_default();
-    let v_fluff: Vec<Message> = bincode::deserialize(&b_fluff[..]).unwrap_or_default();
-    v_fluff
+fn extract_fluff(s: &dyn Storage) -> Result<Vec<Message>, is2fp_error::Ip2pError> {
+    let v_fluff: Option<Vec<Message>> = Store::get(s, Namespace::Fluff, "")?;
+    Ok(v_fluff.unwrap_or_default())
 }
 
-fn update_fluff(v: Vec<Message>) {
+fn update_fluff(s: &dyn Storage, v: Vec<Message>) {
     log::info!("updating fluff");
-    let l = &db::DATABASE_LOCK;
-    let k = FLUFF_KEY.as_bytes().to_vec();
-    db::DatabaseEnvironment::delete(&l.env, &l.handle, &k)
-        .unwrap_or_else(|_| log::error!("failed to clear fluff"));
-    let b_v = bincode::serialize(&v).unwrap_or_default();
-    db::write_chunks(&l.env, &l.handle, &k, &b_v)
+    Store::put(s, Namespace::Fluff, "", &v)
         .unwrap_or_else(|_| log::error!("failed to update fluff"));
 }
 
+/// Drop the fluff messages selected by `expired`, which receives the
+///
+/// queue oldest first. Returns the number of messages removed.
+pub fn prune_fluff<F>(s: &dyn Storage, expired: F) -> Result<usize, is2fp_error::Ip2pError>
+where
+    F: FnOnce(&[Message]) -> Vec<usize>,
+{
+    let _fluff = lock_fluff();
+    extract_fluff(s).and_then(|fluff| {
+        let remove = expired(&fluff);
+        if remove.is_empty() {
+            return Ok(0);
+        }
+        let kept: Vec<Message> = fluff
+            .into_iter()
+            .enumerate()
+            .filter(|(i, _)| !remove.contains(i))
+            .map(|(_, m)| m)
+            .collect();
+        Store::put(s, Namespace::Fluff, "", &kept)?;
+        Ok(remove.len())
+    })
+}
+
 /// Consume a message over i2p relay from a random peer.
 ///
 /// Solve the proof-of-work and timestamp the message into LMDB.
 ///
 /// Fluff propagation vector is consumed by the network event loop on
 ///
-/// a randomly, rotating basis. A `Mutex<bool>` prevents access while
+/// a randomly, rotating basis. A `Mutex<()>` prevents access while
 ///
-/// mutating the vector.
-pub fn inject_fluff(j_msg: Json<Message>) -> Result<(), is2fp_error::Ip2pError> {
-    *IS_FLUFF_LOCKED.lock().unwrap() = true;
-    use::std::time::{SystemTime, UNIX_EPOCH };
+/// mutating the vector and is released when this returns.
+pub fn inject_fluff(s: &dyn Storage, j_msg: Json<Message>) -> Result<(), is2fp_error::Ip2pError> {
     log::info!("injecting fluff msg: {}", &j_msg.mid.clone());
-    let l = &db::DATABASE_LOCK;
-    let k = FLUFF_KEY.as_bytes().to_vec();
-    let b_old_fluff = db::DatabaseEnvironment::read(&l.env, &l.handle, &k)
-        .unwrap_or_default();
-    let mut old_fluff: Vec<Message> = bincode::deserialize(&b_old_fluff[..])
-        .unwrap_or_default();
-    let start = SystemTime::now();
-    let created = start.duration_since(UNIX_EPOCH).map_err(|_| is2fp_error::Ip2pError::Unknown)?.as_secs(); 
+    let created = get_unix_time()?;
     let data = j_msg.data.clone();
     let pow_problem = j_msg.pow_problem.clone();
     let pow_solution = do_pow(&pow_problem)?;
     let m_type = MessageType::Fluff;
     let new_msg: Message = Message { created, data, m_type, pow_problem, pow_solution, ..Default::default() };
+    let _fluff = lock_fluff();
+    let mut old_fluff: Vec<Message> = extract_fluff(s)?;
     old_fluff.push(new_msg);
-    db::DatabaseEnvironment::delete(&l.env, &l.handle, &k)
-        .unwrap_or_else(|_| log::error!("failed to delete fluff injection vector"));
-    let new_fluff = bincode::serialize(&old_fluff).unwrap_or_default();
-    db::write_chunks(&l.env, &l.handle, &k, &new_fluff)
+    Store::put(s, Namespace::Fluff, "", &old_fluff)
         .unwrap_or_else(|_| log::error!("failed to write new fluff injection vector"));
-    *IS_FLUFF_LOCKED.lock().unwrap() = false;
     Ok(())
 }
 
@@ -287,10 +369,19 @@ fn do_pow(problem: &String) -> Result<String, is2fp_error::Ip2pError> {
     Err(is2fp_error::Ip2pError::PowError)
 }
 
-pub async fn run_network() {
+/// Run the swarm until shutdown. Pending fluff is propagated once more
+///
+/// before returning.
+pub async fn run_network(
+    s: SharedStorage,
+    backend: SharedBackend,
+    rotation: SharedRotation,
+    mut shutdown: watch::Receiver<bool>,
+) {
     log::info!("IS2FP Console v0.1.0-alpha\n
                 add peer /ip4/<IP>/tcp/<PORT>/p2p/<PEER_ID>\n
-                send <MESSAGE>");
+                send <MESSAGE>\n
+                rotate");
     // fluff probability and stem extension timeout will be randomized per message
     let mut node = DandelionNode::new(
         0.0,
@@ -305,24 +396,56 @@ pub async fn run_network() {
     node.subscribe(&fluff_topic).unwrap();
     // Read from standard input for chat
     let mut stdin = io::BufReader::new(io::stdin()).lines();
+    let mut stopping = false;
+    let mut announce = rotation.subscribe();
+    let stems = Arc::new(StemClients::new(stem::StemIsolation::from_env()));
+    let mut relay_refresh = tokio::time::interval(Duration::from_secs(RELAY_REFRESH_INTERVAL));
     // Kick it off
     loop {
         // Use network fluff as millisecond range generated randomly on network event loop
         let r_tick = rand::random_range(0..NETWORK_FLUFF);
         let tick = tokio::time::sleep(Duration::from_millis(r_tick));
-        let fluff_msgs: Vec<Message> = extract_fluff();
-        let mut failed_msgs: Vec<Message> = Vec::new();
-        if !fluff_msgs.is_empty() && !*IS_FLUFF_LOCKED.lock().unwrap() {
-            for m in fluff_msgs {    
-                let b_msg = bincode::serialize(&m).unwrap_or_default();
-                if let Err(e) = node.broadcast_message(b_msg, fluff_topic.clone()) {
-                    log::error!("fluff propagation failed for msg id: {} because: {:?}", &m.mid, e);
-                    failed_msgs.push(m);
+        // skip this round while the queue is being changed
+        if let Some(_fluff) = try_lock_fluff() {
+            let fluff_msgs: Vec<Message> = extract_fluff(s.as_ref()).unwrap_or_else(|e| {
+                log::error!("failed to extract fluff: {:?}", e);
+                Vec::new()
+            });
+            let mut failed_msgs: Vec<Message> = Vec::new();
+            if !fluff_msgs.is_empty() {
+                for m in fluff_msgs {
+                    let b_msg = bincode::serialize(&m).unwrap_or_default();
+                    if let Err(e) = node.broadcast_message(b_msg, fluff_topic.clone()) {
+                        log::error!("fluff propagation failed for msg id: {} because: {:?}", &m.mid, e);
+                        failed_msgs.push(m);
+                    }
                 }
+                update_fluff(s.as_ref(), failed_msgs);
             }
-            update_fluff(failed_msgs);
+        }
+        if stopping {
+            log::info!("network stopped");
+            return;
         }
         select! {
+            _ = shutdown.changed() => {
+                // drain the fluff queue on the next pass
+                stopping = true;
+            }
+            Ok(()) = announce.changed() => {
+                // tell connected peers about the rotated address
+                let mut msg: Message = Default::default();
+                msg.data = announce.borrow_and_update().clone();
+                msg.m_type = MessageType::B32Exchange;
+                let b_msg = bincode::serialize(&msg).unwrap_or_default();
+                let peers = node.swarm.connected_peers().cloned().collect::<Vec<_>>();
+                for peer_id in peers {
+                    let topic = gossipsub::IdentTopic::new(format!("stem-{}", &peer_id));
+                    if let Err(e) = node.broadcast_message(b_msg.clone(), topic) {
+                        log::error!("b32 announcement to {:?} failed, {:?}", &peer_id, e);
+                    }
+                }
+            }
             Ok(Some(line)) = stdin.next_line() => {
                 if line.starts_with("add peer ") {
                     let address = &line.split("add peer ").collect::<Vec<&str>>().join("");
@@ -337,13 +460,21 @@ pub async fn run_network() {
                     // TODO: send to invisible stem extension
                     let mut msg: Message = Default::default();
                     msg.data = String::from(p_msg);
-                    let peers = node.swarm.connected_peers().collect::<Vec<_>>();
-                    select_invisible_stem(msg, peers).await
-                        .unwrap_or_else(|_| log::error!("failed to select invisible stem"));
+                    let peers = node.swarm.connected_peers().cloned().collect::<Vec<_>>();
+                    // building a client destination takes a while, keep the swarm polled
+                    let (s, backend, stems) = (s.clone(), backend.clone(), stems.clone());
+                    tokio::spawn(async move {
+                        let peers = peers.iter().collect::<Vec<_>>();
+                        select_invisible_stem(s.as_ref(), &backend, &stems, msg, peers).await
+                            .unwrap_or_else(|_| log::error!("failed to select invisible stem"));
+                    });
                     // TODO: option for clear message broadcasting (i.e. debug mode)
                     //if let Err(e) = node.broadcast_message(b_msg, fluff_topic.clone()) {
                     //    log::error!("Publish error: {e:?}");
                     //}
+                } else if line == "rotate" {
+                    log::info!("rotating relay server address");
+                    rotation.request();
                 }
             }
             event = node.swarm.select_next_some() => match event {
@@ -371,7 +502,7 @@ pub async fn run_network() {
                     let msg: Message = bincode::deserialize(&message.data).unwrap_or_default();
                     log::info!("anon: {}", &msg.data);
                     let local_peer_id = node.swarm.local_peer_id();
-                    if let Err(e) = handle_messages(msg.clone(), peer_id, *local_peer_id) {
+                    if let Err(e) = handle_messages(s.as_ref(), msg.clone(), peer_id, *local_peer_id) {
                         log::error!("failed to handle {:?}: {:?}", &msg.m_type, e);
                     }
                 }
@@ -384,7 +515,7 @@ pub async fn run_network() {
                     tokio::time::sleep(Duration::from_secs(3)).await; 
                     // execute b32 address exchange
                     let mut msg: Message = Default::default();
-                    msg.data = i2p::get_destination().unwrap_or_default();
+                    msg.data = i2p::get_destination(s.as_ref()).unwrap_or_default();
                     msg.m_type = MessageType::B32Exchange;
                     let b_msg = bincode::serialize(&msg).unwrap_or_default();
                     let topic = gossipsub::IdentTopic::new(format!("stem-{}", &peer_id));
@@ -398,6 +529,12 @@ pub async fn run_network() {
                 },
                 _ => {}
             },
+            _ = relay_refresh.tick() => {
+                // keep relays we are still connected to from expiring
+                let peers = node.swarm.connected_peers().map(|p| p.to_string()).collect::<Vec<_>>();
+                retention::refresh_relays(s.as_ref(), &peers)
+                    .unwrap_or_else(|e| log::error!("failed to refresh relays: {:?}", e));
+            }
             _ = tick => {
                 // exit the network loop and check for fluff propagation messages
                 continue;
@@ -413,31 +550,61 @@ pub async fn run_network() {
 /// The initial server startup won't output
 ///
 /// the i2p fluff propagation b32 address.
-pub async fn start_up() -> Result<(), is2fp_error::Ip2pError> {
+pub async fn start_up(
+    s: SharedStorage,
+    backend: SharedBackend,
+    shutdown: SharedShutdown,
+) -> Result<(), is2fp_error::Ip2pError> {
     info!("dandelion-is2fp is starting up");
-    reset_i2p_status()?;
-    if let Err(e) = i2p::start() {
+    i2p::reset_status(s.as_ref())?;
+    let mut lifecycle = i2p::start(s.clone(), backend.clone()).map_err(|e| {
         log::error!("failed to start i2p: {:?}", e);
-    };
+        e
+    })?;
     // start async background tasks here
     {
-        tokio::spawn(async move { 
+        let network_storage = s.clone();
+        let mut i2p_shutdown = shutdown.subscribe();
+        let network_shutdown = shutdown.subscribe();
+        let rotation: SharedRotation = Default::default();
+        shutdown.track(tokio::spawn(rotation::run(
+            s.clone(),
+            backend.clone(),
+            rotation.clone(),
+            shutdown.subscribe(),
+        )));
+        shutdown.track(tokio::spawn(health::run(s.clone(), backend.clone(), shutdown.subscribe())));
+        shutdown.track(tokio::spawn(async move {
+                // start the swarm as soon as the tunnels are up
                 loop {
-                    let is_i2p_online = i2p::check_connection().await;
-                    let i2p_status = is_i2p_online.unwrap_or(i2p::ProxyStatus::Opening);
-                    if i2p_status == i2p::ProxyStatus::Opening {
-                        log::warn!("i2p has not warmed up yet, check wrapper.log");
-                    } else {
-                        break;
+                    let state = lifecycle.borrow_and_update().clone();
+                    match state {
+                        i2p::Lifecycle::Ready | i2p::Lifecycle::Degraded => break,
+                        i2p::Lifecycle::Failed(reason) => {
+                            log::error!("i2p failed to start: {}", reason);
+                            return;
+                        }
+                        i2p::Lifecycle::Stopped => return,
+                        state => log::info!("waiting for i2p ({:?}), check wrapper.log", state),
+                    }
+                    select! {
+                        // the sender is dropped after the last startup transition
+                        changed = lifecycle.changed() => if changed.is_err() {
+                            let state = lifecycle.borrow().clone();
+                            if !matches!(state, i2p::Lifecycle::Ready | i2p::Lifecycle::Degraded) {
+                                return;
+                            }
+                        },
+                        _ = i2p_shutdown.changed() => return,
                     }
-                    std::thread::sleep(std::time::Duration::from_secs(60));
                 }
                 log::info!("i2p fluff propagation server online");
                 // i2p relay server is up, start the swarm
-                run_network().await;
-        });
+                run_network(network_storage, backend, rotation, network_shutdown).await;
+        }));
+        shutdown.track(tokio::spawn(retention::run(s.clone(), shutdown.subscribe())));
     }
-    let destination = i2p::get_destination();
+    let destination = i2p::get_destination(s.as_ref());
     info!("relay server address - {}", destination?);
     Ok(())
 }
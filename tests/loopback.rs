@@ -0,0 +1,124 @@
+//! Runs several nodes on the loopback i2p emulator and sends a message
+//!
+//! through B32Exchange, invisible stem and fluff propagation.
+
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::{
+        Child,
+        ChildStdin,
+        Command,
+        Stdio,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// Seconds to wait for each step
+const TIMEOUT: u64 = 120;
+
+/// An is2fp process and the log lines it wrote so far
+struct Node {
+    child: Child,
+    stdin: ChildStdin,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl Node {
+    fn start(name: &str, registry: &Path, root: &Path) -> Node {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|l| l.local_addr())
+            .expect("free port")
+            .port();
+        let mut child = Command::new(env!("CARGO_BIN_EXE_is2fp"))
+            .env("RUST_LOG", "is2fp=info")
+            .env("IS2FP_I2P_BACKEND", "loopback")
+            .env("IS2FP_LOOPBACK_DIR", registry)
+            .env("IS2FP_DATA_DIR", root.join(name))
+            .env("IS2FP_PORT", port.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("start is2fp");
+        let stdin = child.stdin.take().expect("stdin");
+        let stderr = child.stderr.take().expect("stderr");
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let lines = log.clone();
+        std::thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                lines.lock().unwrap().push(line);
+            }
+        });
+        Node { child, stdin, log }
+    }
+    /// First log line containing `pattern`
+    fn wait_for(&self, pattern: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+        while Instant::now() < deadline {
+            if let Some(line) = self.log.lock().unwrap().iter().find(|l| l.contains(pattern)) {
+                return line.clone();
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        panic!("no log line containing {:?}:\n{}", pattern, self.log.lock().unwrap().join("\n"));
+    }
+    fn logged(&self, pattern: &str) -> bool {
+        self.log.lock().unwrap().iter().any(|l| l.contains(pattern))
+    }
+    /// Loopback address the swarm listens on
+    fn address(&self) -> String {
+        let line = self.wait_for("listening on /ip4/127.0.0.1/");
+        line.split("listening on ").nth(1).expect("address").trim().to_string()
+    }
+    fn console(&mut self, command: &str) {
+        writeln!(self.stdin, "{}", command).expect("console");
+    }
+}
+
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[test]
+fn stem_to_fluff_test() {
+    let root: PathBuf = std::env::temp_dir().join(format!("is2fp-loopback-test-{}", std::process::id()));
+    let registry = root.join("registry");
+    let mut nodes: Vec<Node> = ["a", "b", "c"].iter().map(|n| Node::start(n, &registry, &root)).collect();
+    for node in &nodes {
+        node.wait_for("i2p fluff propagation server online");
+    }
+    let addresses: Vec<String> = nodes.iter().map(Node::address).collect();
+    // connect every node, peers with another connection exchange b32 addresses
+    nodes[0].console(&format!("add peer {}", addresses[1]));
+    nodes[0].console(&format!("add peer {}", addresses[2]));
+    std::thread::sleep(Duration::from_secs(5));
+    nodes[1].console(&format!("add peer {}", addresses[2]));
+    nodes[2].wait_for("writing new relay");
+    nodes[2].console("send hello over loopback");
+    nodes[2].wait_for("relay success");
+    let deadline = Instant::now() + Duration::from_secs(TIMEOUT);
+    while !nodes.iter().any(|n| n.logged("anon: hello over loopback")) {
+        assert!(Instant::now() < deadline, "message was not fluffed");
+        std::thread::sleep(Duration::from_millis(200));
+    }
+    drop(nodes);
+    let _ = std::fs::remove_dir_all(&root);
+}